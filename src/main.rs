@@ -1,4 +1,5 @@
 use crate::molang::MolangParser;
+use std::collections::HashMap;
 use std::time::Instant;
 
 #[macro_use]
@@ -14,8 +15,7 @@ fn test_performance() {
 	let start = Instant::now();
 
 	for _i in 0..100_000 {
-		let input = "false ? 5 : (20 * math.pow(2+2, 2))".to_string();
-		parser.parse(input);
+		parser.parse("false ? 5 : (20 * math.pow(2+2, 2))");
 	}
 
 	let duration = start.elapsed();
@@ -23,10 +23,71 @@ fn test_performance() {
 	println!("Output: in {:?}", duration);
 }
 
+fn test_slice_performance() {
+	let parser = MolangParser::new();
+	let script = parser.compile("v.count = 0; loop(10, {v.count = v.count + 1}); return v.count;");
+
+	let start = Instant::now();
+	let mut variables = HashMap::new();
+	for _i in 0..100_000 {
+		script.eval(&mut variables);
+	}
+	println!("HashMap variables: in {:?}", start.elapsed());
+
+	let start = Instant::now();
+	let mut variables = vec![0.0; script.var_count()];
+	for _i in 0..100_000 {
+		script.eval_slice(&mut variables);
+	}
+	println!("Slice variables: in {:?}", start.elapsed());
+}
+
+fn test_cache_miss_performance() {
+	// Every tenth input is new, the rest are cache hits
+	let inputs: Vec<String> = (0..100_000).map(|i| format!("v.x * {}", i / 10)).collect();
+
+	let mut parser = MolangParser::new();
+	let start = Instant::now();
+	for input in inputs.iter() {
+		parser.parse(input);
+	}
+	println!("Borrowed inputs: in {:?}", start.elapsed());
+
+	let mut parser = MolangParser::new();
+	let start = Instant::now();
+	for input in inputs {
+		parser.parse_owned(input);
+	}
+	println!("Owned inputs: in {:?}", start.elapsed());
+}
+
+fn test_closure_performance() {
+	let parser = MolangParser::new();
+	let mut variables = HashMap::new();
+	variables.insert("variable.y".to_string(), 3.0.into());
+
+	let script = parser.compile("math.sin(v.x * 90) * (v.y > 2 ? math.pow(v.y, 2) : 1) + math.clamp(v.x, 0, 1)");
+	let start = Instant::now();
+	for _i in 0..100_000 {
+		script.eval(&mut variables);
+	}
+	println!("Tree evaluation: in {:?}", start.elapsed());
+
+	let function = script.into_fn();
+	let start = Instant::now();
+	for _i in 0..100_000 {
+		function(&mut variables);
+	}
+	println!("Closure evaluation: in {:?}", start.elapsed());
+}
+
 
 fn main() {
 
 	test_performance();
+	test_slice_performance();
+	test_closure_performance();
+	test_cache_miss_performance();
 
 }
 
@@ -36,7 +97,7 @@ mod tests {
 		use crate::molang::MolangParser;
 		let mut parser = MolangParser::new();
 
-		parser.parse(input.to_string())
+		parser.parse(input)
 	}
 	#[test]
 	fn basic() {
@@ -47,6 +108,14 @@ mod tests {
 		assert_eq!(run("2*-(2/2)"), -2.0);
 	}
 	#[test]
+	fn unary_plus() {
+		assert_eq!(run("+5"), 5.0);
+		assert_eq!(run("3 * +2"), 6.0);
+		assert_eq!(run("-+4"), -4.0);
+		assert_eq!(run("v.x = +3; return v.x;"), 3.0);
+		assert_eq!(run("5 - -3"), 8.0);
+	}
+	#[test]
 	fn order_of_operation() {
 		assert_eq!(run("1 + 1 * 2"), 3.0);
 	}
@@ -59,6 +128,11 @@ mod tests {
 		assert_eq!(run("10 * 0.2f",), 2.0);
 	}
 	#[test]
+	fn single_precision() {
+		// In f64 this would be 16777218, in f32 each +1 is lost to rounding
+		assert_eq!(run("16777216 + 1 + 1"), 16777216.0);
+	}
+	#[test]
 	fn order_of_division() {
 		assert_eq!(run("12 / 2 / 2"), 3.0);
 	}
@@ -67,26 +141,98 @@ mod tests {
 		assert_eq!(run("true ? 10"), 10.0);
 	}
 	#[test]
+	fn binary_false() {
+		assert_eq!(run("false ? 10"), 0.0);
+		assert_eq!(run("v.x = 1; false ? v.x = 9; return v.x;"), 1.0);
+		assert_eq!(run("v.x = 1; true ? v.x = 9; return v.x;"), 9.0);
+	}
+	#[test]
 	fn ternary() {
 		assert_eq!(run("false ? 5 : 10"), 10.0);
 	}
 	#[test]
+	fn nested_ternary() {
+		assert_eq!(run("0 ? 1 : 1 ? 2 : 3"), 2.0);
+		assert_eq!(run("1 ? 2 : 0 ? 3 : 4"), 2.0);
+		assert_eq!(run("1 ? 0 ? 5 : 6 : 7"), 6.0);
+		assert_eq!(run("0 ? 0 ? 5 : 6 : 7"), 7.0);
+	}
+	#[test]
 	fn greater_or_equal() {
 		assert_eq!(run("3 >= 4"), 0.0);
 	}
 	#[test]
+	fn comparison_chaining() {
+		assert_eq!(run("1 < 2 < 3"), 1.0);
+		assert_eq!(run("3 > 2 > 1"), 0.0);
+		assert_eq!(run("1 <= 2 < 1"), 0.0);
+		assert_eq!(run("2 == 2 == 1"), 1.0);
+	}
+	#[test]
+	fn negation_position() {
+		assert_eq!(run("2 * !0"), 2.0);
+		assert_eq!(run("!v.x == 0"), 0.0);
+		assert_eq!(run("v.x = 1; return !v.x == 0;"), 1.0);
+		assert_eq!(run("!(1 == 2)"), 1.0);
+		assert_eq!(run("1 != 2"), 1.0);
+		assert_eq!(run("v.a = 1; return v.a && !v.b;"), 1.0);
+	}
+	#[test]
 	fn multi_line() {
 		assert_eq!(run("temp.test = 33; return temp.test * 2"), 66.0);
 	}
 	#[test]
+	fn comparison_in_assignment() {
+		assert_eq!(run("v.flag = 2 == 2; return v.flag;"), 1.0);
+		assert_eq!(run("v.x = 3 <= 4; return v.x;"), 1.0);
+		assert_eq!(run("v.x = 3 != 4; return v.x;"), 1.0);
+		assert_eq!(run("v.x = 2; return v.x == 2;"), 1.0);
+		assert_eq!(run("v.x = 2; return v.x >= 3;"), 0.0);
+		assert_eq!(run("v.xy ="), 0.0);
+	}
+	#[test]
 	fn return_value() {
 		assert_eq!(run("temp.test = 4; return temp.test; return 5;"), 4.0);
 	}
 	#[test]
+	fn trailing_semicolon() {
+		assert_eq!(run("1+1;"), 2.0);
+	}
+	#[test]
+	fn empty_statements() {
+		assert_eq!(run(";"), 0.0);
+		assert_eq!(run("1+1;;"), 2.0);
+		assert_eq!(run("v.x=5;;return v.x;"), 5.0);
+	}
+	#[test]
+	fn unicode_identifiers() {
+		assert_eq!(run("v.größe = 2; return v.größe && 1;"), 1.0);
+		assert_eq!(run("v.ä ?? 1"), 1.0);
+		assert_eq!(run("v.a?ä ?? 1"), 0.0);
+		assert_eq!(run("v.a&é && 1"), 0.0);
+		assert_eq!(run("v.ä1e-1"), -1.0);
+	}
+	#[test]
+	fn null_coalescing() {
+		assert_eq!(run("v.unset ?? 5"), 5.0);
+		assert_eq!(run("v.x = 2; return v.x ?? 5;"), 2.0);
+	}
+	#[test]
+	fn is_defined() {
+		assert_eq!(run("v.x = 0; return math.is_defined(v.x);"), 1.0);
+		assert_eq!(run("math.is_defined(v.unset)"), 0.0);
+	}
+	#[test]
 	fn math() {
 		assert_eq!(run("Math.pow(Math.clamp(500, 0, 3), 2)"), 9.0);
 	}
 	#[test]
+	fn math_constants() {
+		assert_eq!(run("math.e"), std::f32::consts::E);
+		assert_eq!(run("math.pi * 2"), std::f32::consts::PI * 2.0);
+		assert_eq!(run("math.unknown_constant"), 0.0);
+	}
+	#[test]
 	fn aliases() {
 		assert_eq!(run("t.a = 6; variable.b = 2; return temp.a / v.b;"), 3.0);
 	}
@@ -99,6 +245,11 @@ mod tests {
 		assert_eq!(run("Math.round(Math.acos(-1) + Math.atan2(2, 4))"), 207.0);
 	}
 	#[test]
+	fn angle_conversion() {
+		assert!((run("math.to_rad(180)") - std::f32::consts::PI).abs() < 0.0001);
+		assert!((run("math.to_deg(math.pi)") - 180.0).abs() < 0.0001);
+	}
+	#[test]
 	fn query_in_range() {
 		assert_eq!(run("q.in_range(1, 0, 2) && !query.in_range(55, 1, 5)"), 1.0);
 	}
@@ -107,10 +258,937 @@ mod tests {
 		assert_eq!(run("q.approx_eq(2, 2.00000000002) && !q.approx_eq(2, 2, 3)"), 1.0);
 	}
 	#[test]
+	fn return_from_loop() {
+		use crate::molang::MolangParser;
+		let mut parser = MolangParser::new();
+		let result = parser.parse("v.i = 0; loop(10, {(v.i > 2) ? return v.i; v.i = v.i + 1;}); v.after = 1; return 0;");
+		assert_eq!(result, 3.0);
+		assert_eq!(parser.parse("v.after"), 0.0);
+	}
+	#[test]
 	fn loops() {
 		assert_eq!(run("v.count = 0; loop(10, {v.count = v.count + 1}); return v.count;"), 10.0);
 	}
 	#[test]
+	fn loop_count_rounding() {
+		assert_eq!(run("v.count = 0; loop(2.9, {v.count = v.count + 1}); return v.count;"), 3.0);
+		assert_eq!(run("v.count = 0; loop(2.5, {v.count = v.count + 1}); return v.count;"), 2.0);
+	}
+	#[test]
+	fn integer_results() {
+		use crate::molang::MolangParser;
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.parse_int("2.5"), 2);
+		assert_eq!(parser.parse_int("-2.5"), -2);
+		assert_eq!(parser.parse_int("2.9"), 3);
+		assert_eq!(parser.parse_int("3.5"), 4);
+	}
+	#[test]
+	fn builtin_list() {
+		use crate::molang::MolangParser;
+		let builtins = MolangParser::builtins();
+		assert!(builtins.iter().any(|builtin| builtin.name == "math.pow" && builtin.arity == 2));
+		assert!(builtins.iter().any(|builtin| builtin.name == "math.clamp" && builtin.arity == 3));
+		assert!(builtins.iter().any(|builtin| builtin.name == "query.approx_eq" && builtin.variadic));
+		assert!(builtins.iter().any(|builtin| builtin.name == "math.round" && builtin.min_arity == 1 && builtin.arity == 2));
+		assert!(builtins.iter().any(|builtin| builtin.name == "math.random" && builtin.min_arity == 1));
+		assert!(builtins.iter().all(|builtin| builtin.min_arity <= builtin.arity));
+	}
+	#[test]
+	fn unknown_function() {
+		use crate::molang::{MolangParser, MolangError};
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.parse("math.bogus(v.x = 5); return v.x;"), 5.0);
+		assert_eq!(parser.try_parse("math.bogus(1)"), Err(MolangError::UnknownFunction("math.bogus".to_string())));
+		assert_eq!(parser.try_parse("math.bogus"), Err(MolangError::UnknownFunction("math.bogus".to_string())));
+	}
+	#[test]
+	fn member_assignment() {
+		use crate::molang::{MolangParser, MolangError};
+		assert_eq!(run("v.pos.x = 1; return v.pos.x;"), 1.0);
+		assert_eq!(run("v.pos.x = 1; v.pos.y = 2; return v.pos.x + v.pos.y;"), 3.0);
+		assert_eq!(run("v.pos.x = 1; return v.pos.x == 1;"), 1.0);
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.try_parse("v.list[2] = 9"), Err(MolangError::InvalidAssignmentTarget("v.list[2]".to_string())));
+	}
+	#[test]
+	fn eval_const() {
+		let parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.compile("math.pow(2+2, 2)").eval_const(), Some(16.0));
+		assert_eq!(parser.compile("v.x + 1").eval_const(), None);
+	}
+	#[test]
+	fn formatted_output() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse_formatted("math.pi", 4), "3.1416");
+		assert_eq!(parser.parse_formatted("math.pi/2", 2), "1.57");
+		assert_eq!(parser.parse_formatted("0.5 + 0.5", 3), "1");
+		assert_eq!(parser.parse_formatted("20", 0), "20");
+		assert_eq!(parser.parse_formatted("-0.001", 2), "0");
+		assert_eq!(parser.parse_formatted("-0.4", 0), "0");
+		assert_eq!(parser.parse_formatted("-0.25", 1), "-0.2");
+	}
+	#[test]
+	fn logical_precedence() {
+		assert_eq!(run("1 || 0 && 0"), 1.0);
+		assert_eq!(run("0 && 0 || 1"), 1.0);
+		assert_eq!(run("(1 || 0) && 0"), 0.0);
+	}
+	#[test]
+	fn stepwise_evaluation() {
+		let mut parser = crate::molang::MolangParser::new();
+		let mut steps = Vec::new();
+		let result = parser.eval_stepwise("v.a = 2; v.b = v.a * 3; return v.a + v.b; v.a = 10;", |index, value, variables| {
+			steps.push((index, value, variables.get("variable.b").map(|value| value.as_number())));
+		});
+		assert_eq!(result, 8.0);
+		assert_eq!(steps, vec![(0, 0.0, None), (1, 0.0, Some(6.0)), (2, 8.0, Some(6.0))]);
+	}
+	#[test]
+	fn clamped_lerp() {
+		assert_eq!(run("math.lerp(0, 10, 2)"), 20.0);
+		assert_eq!(run("math.lerpc(0, 10, 2)"), 10.0);
+		assert_eq!(run("math.lerpc(0, 10, -1)"), 0.0);
+		assert_eq!(run("math.lerpc(0, 10, 0.5)"), 5.0);
+	}
+	#[test]
+	fn result_kind() {
+		use crate::molang::{MolangParser, ValueKind};
+		let parser = MolangParser::new();
+		assert_eq!(parser.compile("3 >= 4").result_kind(), ValueKind::Bool);
+		assert_eq!(parser.compile("1+1").result_kind(), ValueKind::Number);
+		assert_eq!(parser.compile("!v.x").result_kind(), ValueKind::Bool);
+		assert_eq!(parser.compile("v.x ? 1 : 2").result_kind(), ValueKind::Number);
+		assert_eq!(parser.compile("v.x ? 1 : v.y").result_kind(), ValueKind::Unknown);
+		assert_eq!(parser.compile("'a' ?? 'b'").result_kind(), ValueKind::String);
+		assert_eq!(parser.compile("'a' ?? 1").result_kind(), ValueKind::Unknown);
+	}
+	#[test]
+	fn uncached_parsing() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.parse("1 + 2 * 3");
+		assert_eq!(parser.cache_len(), 1);
+		assert_eq!(parser.parse_uncached("v.x = 4; return v.x * 2;"), 8.0);
+		assert_eq!(parser.cache_len(), 1);
+		assert_eq!(parser.parse("v.x"), 4.0);
+	}
+	#[test]
+	fn boolean_keywords() {
+		assert_eq!(run("true && false"), 0.0);
+		assert_eq!(run("true || false"), 1.0);
+		assert_eq!(run("true ? 5 : 10"), 5.0);
+		assert_eq!(run("false ? 5 : 10"), 10.0);
+		assert_eq!(run("2 * true"), 2.0);
+		assert_eq!(run("1 + true"), 2.0);
+		assert_eq!(run("!false"), 1.0);
+		assert_eq!(run("true == 1"), 1.0);
+		assert_eq!(run("math.max(false, true)"), 1.0);
+		assert_eq!(run("v.x = true; return v.x;"), 1.0);
+	}
+	#[test]
+	fn arrays() {
+		assert_eq!(run("v.a = [1,2,3]; return math.array_length(v.a);"), 3.0);
+		assert_eq!(run("v.a = [4, 5, 6]; return v.a[1] + v.a[2];"), 11.0);
+		assert_eq!(run("v.a = [4, 5, 6]; return v.a[1.7];"), 5.0);
+		assert_eq!(run("v.a = [4, 5, 6]; return v.a[3] + v.a[-1];"), 0.0);
+		assert_eq!(run("v.a = []; return math.array_length(v.a);"), 0.0);
+		assert_eq!(run("v.n = 5; return math.array_length(v.n) + v.n[0];"), 0.0);
+		// Trailing multi-byte characters aren't part of the literal
+		assert_eq!(run("[1]ä"), 0.0);
+		assert_eq!(run("[v.x]ö"), 0.0);
+		assert_eq!(run("v.a = [1, math.max(2, 3), (4)]; return v.a[1] * v.a[2];"), 12.0);
+	}
+	#[test]
+	fn negative_zero() {
+		let mut parser = crate::molang::MolangParser::new();
+		let result = parser.parse("math.trunc(-0.5)");
+		assert_eq!(result, 0.0);
+		assert!(result.is_sign_positive());
+		assert_eq!(format!("{}", result), "0");
+		assert!(parser.parse("-0").is_sign_positive());
+		assert!(parser.parse("math.ceil(-0.2) * 1").is_sign_positive());
+	}
+	#[test]
+	fn running_max() {
+		assert_eq!(run("v.peak = 0; loop(3, {v.current = v.current + 1; v.peak = math.max(v.peak, v.current)}); return v.peak;"), 3.0);
+		// Each argument is evaluated exactly once
+		assert_eq!(run("v.count = 0; v.peak = math.max((v.count = v.count + 1), 0); return v.count;"), 1.0);
+	}
+	#[test]
+	fn globals() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_global("variable.gravity", 9.8);
+		parser.set_global("v.list", crate::molang::MolangValue::Array(vec![1.0, 2.0]));
+		parser.parse("v.x = 2");
+		parser.clear_variables();
+		assert_eq!(parser.parse("v.x"), 0.0);
+		assert_eq!(parser.parse("v.gravity"), 9.8);
+		assert_eq!(parser.parse("math.array_length(v.list)"), 2.0);
+		// Local variables shadow globals
+		assert_eq!(parser.parse("v.gravity = 1; return v.gravity;"), 1.0);
+		parser.clear_variables();
+		assert_eq!(parser.parse("v.gravity"), 9.8);
+		let mut context = parser.new_context();
+		assert_eq!(parser.parse_with_context("v.gravity * 2", &mut context), 19.6);
+	}
+	#[test]
+	fn slice_variables() {
+		let parser = crate::molang::MolangParser::new();
+		let script = parser.compile("v.count = 0; loop(10, {v.count = v.count + v.step}); return v.count;");
+		assert_eq!(script.var_count(), 2);
+		let mut variables = vec![0.0; script.var_count()];
+		variables[script.var_id("v.step").unwrap()] = 2.0;
+		assert_eq!(script.eval_slice(&mut variables), 20.0);
+		assert_eq!(variables[script.var_id("variable.count").unwrap()], 20.0);
+		assert_eq!(script.var_id("v.other"), None);
+	}
+	#[test]
+	fn slice_slots() {
+		use crate::molang::ast;
+		let parser = crate::molang::MolangParser::new();
+		let script = parser.compile("loop(3, t.i, {v.sum = v.sum + t.i}); scope({t.i = 10}); return v.sum + t.i;");
+		let mut variables = vec![0.0; script.var_count()];
+		assert_eq!(script.eval_slice(&mut variables), 5.0);
+		assert_eq!(variables[script.var_id("v.sum").unwrap()], 3.0);
+		// Combined expressions get new slots for the variables of both parts
+		let script = ast::add(ast::variable("v.b"), ast::mul(ast::variable("v.a"), ast::variable("v.b")));
+		let mut variables = vec![0.0; script.var_count()];
+		variables[script.var_id("v.a").unwrap()] = 3.0;
+		variables[script.var_id("v.b").unwrap()] = 2.0;
+		assert_eq!(script.eval_slice(&mut variables), 8.0);
+	}
+	#[test]
+	fn string_queries() {
+		use crate::molang::{MolangParser, MolangValue};
+		let mut parser = MolangParser::new();
+		parser.register_query("query.get_name", |_| MolangValue::String("zombie".to_string()));
+		parser.register_query_num("q.sum", |args| args.iter().sum());
+		assert_eq!(parser.parse("query.get_name() == 'zombie'"), 1.0);
+		assert_eq!(parser.parse("q.get_name() != 'skeleton'"), 1.0);
+		assert_eq!(parser.parse("q.get_name() == 'zombie' ? 5 : 10"), 5.0);
+		assert_eq!(parser.parse("q.sum(1, 2, 3) * 2"), 12.0);
+		assert_eq!(parser.parse("q.sum()"), 0.0);
+		assert_eq!(parser.parse("q.unknown(1)"), 0.0);
+		assert_eq!(parser.parse("'a?b' == 'a?b'"), 1.0);
+		assert_eq!(parser.parse("v.name = q.get_name(); return v.name == 'zombie';"), 1.0);
+	}
+	#[test]
+	fn readonly_assignment() {
+		use crate::molang::{MolangParser, MolangError};
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.try_parse("q.x = 5"), Err(MolangError::InvalidAssignmentTarget("q.x".to_string())));
+		assert_eq!(parser.try_parse("context.owner.x = 5"), Err(MolangError::InvalidAssignmentTarget("context.owner.x".to_string())));
+		assert_eq!(parser.try_parse("math.pi = 3"), Err(MolangError::InvalidAssignmentTarget("math.pi".to_string())));
+		assert_eq!(parser.try_parse("q.foo ?= 1"), Err(MolangError::InvalidAssignmentTarget("q.foo".to_string())));
+		assert_eq!(parser.parse("q.foo ?= 1; return q.foo;"), 0.0);
+		assert_eq!(parser.parse("q.x = 5; return q.x;"), 0.0);
+		assert_eq!(parser.try_parse("q.x == 5"), Ok(0.0));
+	}
+	#[test]
+	fn string_whitespace() {
+		assert_eq!(run("'a b' == 'a b'"), 1.0);
+		assert_eq!(run("'a b' == 'ab'"), 0.0);
+		assert_eq!(run("'Zombie' == 'zombie'"), 0.0);
+		assert_eq!(run("V.X = 2;\n\treturn v.x;"), 2.0);
+	}
+	#[test]
+	fn nan_propagation() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse("math.max(math.sqrt(-1), 5)"), 5.0);
+		assert_eq!(parser.parse("math.min(5, math.sqrt(-1))"), 5.0);
+		parser.propagate_nan = true;
+		assert!(parser.parse("math.max(math.sqrt(-1), 5)").is_nan());
+		assert!(parser.parse("math.min(5, math.sqrt(-1))").is_nan());
+		assert_eq!(parser.parse("math.max(2, 5)"), 5.0);
+	}
+	#[test]
+	fn loop_index() {
+		assert_eq!(run("v.sum = 0; loop(4, v.i, {v.sum = v.sum + v.i}); return v.sum;"), 6.0);
+		assert_eq!(run("loop(3, t.i, {t.x = t.i * 2}); return t.x + t.i;"), 6.0);
+		assert_eq!(run("v.count = 0; loop(3, {v.count = v.count + 1}); return v.count;"), 3.0);
+	}
+	#[test]
+	fn reset() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.propagate_nan = true;
+		parser.set_global("v.gravity", 9.8);
+		parser.parse("v.x = 5; t.y = 2;");
+		assert_eq!(parser.cache_len(), 1);
+		parser.reset();
+		assert_eq!(parser.cache_len(), 0);
+		assert_eq!(parser.parse("v.x + t.y + v.gravity"), 0.0);
+		assert!(parser.enable_cache);
+		assert!(parser.propagate_nan);
+	}
+	#[test]
+	fn comma_sequence() {
+		assert_eq!(run("(v.x = 2, v.x * 3)"), 6.0);
+		assert_eq!(run("v.y = 1 ? (v.x = 4, v.x + 1) : 0; return v.y;"), 5.0);
+		assert_eq!(run("math.max((v.x = 2, v.x + 5), 3)"), 7.0);
+		assert_eq!(run("math.min(1, 2)"), 1.0);
+	}
+	#[test]
+	fn die_rolls() {
+		assert_eq!(run("math.die_roll_integer(3, 1, 1)"), 3.0);
+		assert_eq!(run("math.die_roll(-1, 0, 10)"), 0.0);
+		assert_eq!(run("math.die_roll(2.9, 1, 1)"), 2.0);
+		assert_eq!(run("math.random(5, 5)"), 5.0);
+		let inverted = run("math.die_roll_integer(2, 6, 1)");
+		assert!((2.0..=12.0).contains(&inverted));
+		let inverted = run("math.random(10, 0)");
+		assert!((0.0..10.0).contains(&inverted));
+	}
+	#[test]
+	fn evaluation_budget() {
+		use crate::molang::{MolangParser, MolangError};
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.parse_budgeted("v.x = 0; loop(100000, {v.x = v.x + 1}); return v.x;", 1000), Err(MolangError::BudgetExceeded));
+		assert_eq!(parser.parse_budgeted("v.x = 0; loop(10, {v.x = v.x + 1}); return v.x;", 1000), Ok(10.0));
+		assert_eq!(parser.parse("v.x = 0; loop(100000, {v.x = v.x + 1}); return v.x;"), 100000.0);
+	}
+	#[test]
+	fn hex_literals() {
+		assert_eq!(run("0xFF"), 255.0);
+		assert_eq!(run("0x10 + 1"), 17.0);
+		assert_eq!(run("-0x10 * 2"), -32.0);
+		assert_eq!(run("v.x = 0xff; return v.x / 0xF;"), 17.0);
+	}
+	#[test]
+	fn dump_variables() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.parse("v.health = 20; t.list = [1, 2]; v.name = 'zombie'; v.armor = 2.5;");
+		assert_eq!(parser.dump_variables(), "temp.list = [1, 2]\nvariable.armor = 2.5\nvariable.health = 20\nvariable.name = 'zombie'");
+		assert_eq!(parser.variables_iter().count(), 4);
+		assert!(parser.variables_iter().any(|(name, value)| name == "variable.health" && value.as_number() == 20.0));
+	}
+	#[test]
+	fn multiplication_associativity() {
+		assert_eq!(run("8/2*2"), 8.0);
+		assert_eq!(run("2*3*4"), 24.0);
+		let mut parser = crate::molang::MolangParser::new();
+		let (_, tree) = parser.parse_debug("v.a*v.b*v.c");
+		assert_eq!(tree, "Multiply\n  Multiply\n    Variable(variable.a)\n    Variable(variable.b)\n  Variable(variable.c)\n");
+	}
+	#[test]
+	fn multiplication_and_division() {
+		assert_eq!(run("12/2/2"), 3.0);
+		assert_eq!(run("12/2*3"), 18.0);
+		assert_eq!(run("2*6/4"), 3.0);
+		assert_eq!(run("6/3*2"), 4.0);
+		assert_eq!(run("6*3/2"), 9.0);
+		assert_eq!(run("2*3*4"), 24.0);
+		assert_eq!(run("24/2*3/4"), 9.0);
+		assert_eq!(run("1+12/2*3-1"), 18.0);
+	}
+	#[test]
+	fn parse_and_store() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_variable_resolver(Box::new(|name| if name == "query.speed" {Some(3.0)} else {None}));
+		assert_eq!(parser.parse_and_store("q.speed * 2", "v.prev_speed"), 6.0);
+		assert_eq!(parser.parse("variable.prev_speed"), 6.0);
+		assert_eq!(parser.parse_and_store("v.prev_speed + 1", "v.prev_speed"), 7.0);
+		assert_eq!(parser.parse("v.prev_speed"), 7.0);
+	}
+	#[test]
+	fn nested_query_paths() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_variable_resolver(Box::new(|name| match name {
+			"query.item.count" => Some(12.0),
+			"query.equipped_item.is_armor" => Some(1.0),
+			_ => None
+		}));
+		assert_eq!(parser.parse("q.item.count"), 12.0);
+		assert_eq!(parser.parse("query.item.count * 2"), 24.0);
+		assert_eq!(parser.parse("q.equipped_item.is_armor ? q.item.count : 0"), 12.0);
+		assert_eq!(parser.parse("q.item.missing"), 0.0);
+		assert_eq!(parser.parse("q.item"), 0.0);
+		parser.register_query_num("q.item.durability", |args| args.first().copied().unwrap_or(0.0) + 1.0);
+		assert_eq!(parser.parse("q.item.durability(4)"), 5.0);
+	}
+	#[test]
+	fn clone_compiled() {
+		use std::collections::HashMap;
+		let mut parser = crate::molang::MolangParser::new();
+		let script = parser.compile("v.x = v.x + 1; return v.x;");
+		let copy = script.clone();
+		let mut variables = HashMap::new();
+		let mut other_variables = HashMap::new();
+		script.eval(&mut variables);
+		assert_eq!(script.eval(&mut variables), 2.0);
+		assert_eq!(copy.eval(&mut other_variables), 1.0);
+
+		parser.propagate_nan = true;
+		parser.register_query_num("q.two", |_| 2.0);
+		parser.parse("v.x = 5");
+		let mut config = parser.clone_config();
+		assert!(config.propagate_nan);
+		assert_eq!(config.cache_len(), 0);
+		assert_eq!(config.parse("v.x + q.two()"), 2.0);
+	}
+	#[test]
+	fn readonly_evaluation() {
+		use std::collections::HashMap;
+		use crate::molang::{MolangParser, MolangValue, MolangError};
+		let parser = MolangParser::new();
+		let mut variables = HashMap::new();
+		variables.insert("variable.x".to_string(), MolangValue::Number(5.0));
+		assert_eq!(parser.compile("v.x > 3").eval_readonly(&variables), Ok(1.0));
+		assert_eq!(parser.compile("v.x = 5").eval_readonly(&variables), Err(MolangError::InvalidAssignmentTarget("variable.x".to_string())));
+		assert!(parser.compile("loop(2, v.i, {v.i})").eval_readonly(&variables).is_err());
+	}
+	#[test]
+	fn scientific_notation() {
+		assert_eq!(run("100000000"), 100000000.0);
+		assert_eq!(run("123456789"), 123456789.0);
+		assert_eq!(run("1e-10"), 1e-10);
+		assert_eq!(run("1.5e2"), 150.0);
+		assert_eq!(run("1E3 - 1"), 999.0);
+		assert_eq!(run("2 * 1e-3"), 0.002);
+		assert_eq!(run("1e+2+1"), 101.0);
+		assert_eq!(run("v.size = 3; return v.size-1;"), 2.0);
+		assert_eq!(run("0x1e-1"), 29.0);
+	}
+	#[test]
+	fn float_suffix() {
+		assert_eq!(run("0.2f"), 0.2);
+		assert_eq!(run("-1.5f"), -1.5);
+		assert_eq!(run("0.2f * 10"), 2.0);
+	}
+	#[test]
+	fn arrays_from_rust() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_array("v.points", vec![1.0, 2.0, 3.0]);
+		assert_eq!(parser.parse("v.points[2]"), 3.0);
+		assert_eq!(parser.parse("math.array_length(variable.points)"), 3.0);
+		assert_eq!(parser.parse("v.points + 1"), 1.0);
+		parser.parse("v.points = 5");
+		assert_eq!(parser.parse("v.points[0]"), 0.0);
+	}
+	#[test]
+	fn modulo_operator() {
+		assert_eq!(run("5 % 3"), 2.0);
+		assert_eq!(run("10 % 3 % 2"), 1.0);
+		assert_eq!(run("2 * 7 % 4"), 2.0);
+		assert_eq!(run("1 + 7 % 4"), 4.0);
+		assert_eq!(run("7 % -4"), run("math.mod(7, -4)"));
+	}
+	#[test]
+	fn exponent_operator() {
+		assert_eq!(run("2 ^ 8"), 256.0);
+		assert_eq!(run("2 ^ 2 ^ 3"), 256.0);
+		assert_eq!(run("3 * 2 ^ 2"), 12.0);
+		assert_eq!(run("2 ^ -1"), 0.5);
+		assert_eq!(run("-2 ^ 2"), -4.0);
+		assert_eq!(run("(1 + 1) ^ 3"), 8.0);
+	}
+	#[test]
+	fn ast_builder() {
+		use std::collections::HashMap;
+		use crate::molang::{ast, MolangValue};
+		let script = ast::add(ast::number(1.0), ast::variable("v.x"));
+		let mut variables = HashMap::new();
+		variables.insert("variable.x".to_string(), MolangValue::Number(4.0));
+		assert_eq!(script.eval(&mut variables), 5.0);
+		let script = ast::neg(ast::pow(ast::number(2.0), ast::sub(ast::number(4.0), ast::number(1.0))));
+		assert_eq!(script.eval_const(), Some(-8.0));
+	}
+	#[test]
+	fn single_argument_evaluation() {
+		assert_eq!(run("v.x = 0; math.clamp(v.x = v.x + 1, 0, 10); return v.x;"), 1.0);
+		assert_eq!(run("v.x = 0; math.lerp(0, v.x = v.x + 1, 0.5); return v.x;"), 1.0);
+		assert_eq!(run("v.x = 0; query.all(v.x = v.x + 1, 0, 0); return v.x;"), 1.0);
+	}
+	#[test]
+	fn double_negation() {
+		assert_eq!(run("!!5"), 1.0);
+		assert_eq!(run("!!0"), 0.0);
+		assert_eq!(run("!!!1"), 0.0);
+		assert_eq!(run("!!v.x"), 0.0);
+		assert_eq!(run("!!0.5 + 1"), 2.0);
+	}
+	#[test]
+	fn precompile() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.precompile(&["v.x = math.random(0, 1)", "v.y = 2; return v.y;"]);
+		assert!(parser.is_cached("v.x = math.random(0, 1)"));
+		assert!(parser.is_cached("v.y = 2; return v.y;"));
+		assert!(!parser.is_cached("v.z"));
+		assert_eq!(parser.cache_len(), 2);
+		assert_eq!(parser.variables_iter().count(), 0);
+		assert_eq!(parser.parse("v.y = 2; return v.y;"), 2.0);
+		assert_eq!(parser.cache_len(), 2);
+	}
+	#[test]
+	fn nesting_depth() {
+		use crate::molang::{MolangParser, MolangError};
+		let mut parser = MolangParser::new();
+		let nested = "(".repeat(10000) + "1" + &")".repeat(10000);
+		assert_eq!(parser.try_parse(&nested), Ok(1.0));
+		let nested = "(1+".repeat(10000) + "1" + &")".repeat(10000);
+		assert_eq!(parser.try_parse(&nested), Err(MolangError::DepthExceeded));
+		assert_eq!(parser.parse(&nested), 0.0);
+		let chain = vec!["1"; 10000].join("+");
+		assert_eq!(parser.try_parse(&chain), Err(MolangError::DepthExceeded));
+		let chain = vec!["1"; 100].join("+");
+		assert_eq!(parser.try_parse(&chain), Ok(100.0));
+		parser.max_depth = 4;
+		assert_eq!(parser.try_parse("1+1+1+1+1"), Err(MolangError::DepthExceeded));
+		assert_eq!(parser.try_parse("(1)+((2))"), Ok(3.0));
+	}
+	#[test]
+	fn clamp_edge_cases() {
+		assert_eq!(run("math.clamp(5, 0, 10)"), 5.0);
+		assert_eq!(run("math.clamp(-5, 0, 10)"), 0.0);
+		assert_eq!(run("math.clamp(15, 0, 10)"), 10.0);
+		assert_eq!(run("math.clamp(math.sqrt(-1), 2, 10)"), 2.0);
+		assert_eq!(run("math.clamp(15, 10, 0)"), 10.0);
+		assert_eq!(run("math.clamp(-5, 10, 0)"), 0.0);
+		assert_eq!(run("math.clamp(5, 10, 0)"), 5.0);
+		assert_eq!(run("math.clamp(math.sqrt(-1), 10, 0)"), 0.0);
+		assert_eq!(run("math.clamp(5, math.sqrt(-1), 3)"), 3.0);
+	}
+	#[test]
+	fn variable_macro() {
+		let parser = crate::molang::MolangParser::new();
+		let mut variables = crate::molang_vars!{"v.x" => 1.0, "Variable.Y" => 2.0};
+		assert_eq!(parser.compile("v.x + v.y").eval(&mut variables), 3.0);
+		assert!(variables.contains_key("variable.y"));
+		let empty: std::collections::HashMap<String, crate::molang::MolangValue> = crate::molang_vars!{};
+		assert!(empty.is_empty());
+	}
+	#[test]
+	fn vec3_results() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse_vec3("[1, 2, 3]"), [1.0, 2.0, 3.0]);
+		assert_eq!(parser.parse_vec3("t.a = 2; return [t.a, t.a * 2, t.a * 3];"), [2.0, 4.0, 6.0]);
+		assert_eq!(parser.parse_vec3("t.x = 1; t.y = 5; t.z = -1;"), [1.0, 5.0, -1.0]);
+		assert_eq!(parser.parse_vec3("[7]"), [7.0, 0.0, 0.0]);
+		assert_eq!(parser.cache_len(), 4);
+	}
+	#[test]
+	#[cfg(feature = "tracing")]
+	fn trace_hook() {
+		use std::{cell::RefCell, rc::Rc};
+		let events = Rc::new(RefCell::new(Vec::new()));
+		let mut parser = crate::molang::MolangParser::new();
+		let recorded = events.clone();
+		parser.set_trace_hook(Box::new(move |name, value| recorded.borrow_mut().push(format!("{} = {}", name, value))));
+		parser.set_variable_resolver(Box::new(|name| if name == "query.time" {Some(2.5)} else {None}));
+		parser.parse("v.x = 1");
+		assert_eq!(parser.parse("v.x + q.time"), 3.5);
+		assert_eq!(*events.borrow(), vec!["variable.x = 1", "query.time = 2.5"]);
+	}
+	#[test]
+	fn nested_query_evaluation() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.register_query_with_context("query.half_width", |context, _| context.eval("v.width / 2").into());
+		parser.register_query_with_context("query.early_return", |context, _| context.eval("return 4; 8").into());
+		assert_eq!(parser.parse("v.width = 6; return q.half_width() + 1;"), 4.0);
+		assert_eq!(parser.parse("t.a = q.early_return(); return t.a + 1;"), 5.0);
+	}
+	#[test]
+	fn tau_constant() {
+		assert_eq!(run("math.tau"), run("math.pi * 2"));
+		assert_eq!(run("math.tau"), std::f32::consts::TAU);
+		assert_eq!(run("math.tau / 4"), std::f32::consts::FRAC_PI_2);
+	}
+	#[test]
+	fn negative_arguments() {
+		assert_eq!(run("math.pow(2, -2)"), 0.25);
+		assert_eq!(run("math.round(math.atan2(-2, -4))"), -153.0);
+		assert_eq!(run("math.clamp(-5, -10, 0)"), -5.0);
+		assert_eq!(run("math.clamp(-15, -10, -2)"), -10.0);
+		assert_eq!(run("math.min(-1, -2) + math.max(-3, -4)"), -5.0);
+		assert_eq!(run("math.lerp(-2, -4, 0.5)"), -3.0);
+		assert_eq!(run("math.pow(-2, 3)"), -8.0);
+		assert_eq!(run("math.abs(-3 - -1)"), 2.0);
+	}
+	#[test]
+	fn isolated_scope() {
+		assert_eq!(run("temp.x = 1; scope({temp.x = 99}); return temp.x;"), 1.0);
+		assert_eq!(run("scope({temp.y = 2}); return temp.y ?? 5;"), 5.0);
+		assert_eq!(run("v.x = 1; scope({v.x = 3}); return v.x;"), 3.0);
+		assert_eq!(run("t.a = 2; return scope({t.a = t.a * 4; return t.a + 1;}) + t.a;"), 11.0);
+		assert_eq!(run("scope({return 1;}); return 2;"), 2.0);
+		assert_eq!(run("scope("), 0.0);
+		assert_eq!(run("scope()"), 0.0);
+	}
+	#[test]
+	fn rounding_precision() {
+		assert_eq!(run("math.round(1.23456, 2)"), 1.23);
+		assert_eq!(run("math.round(3.14159)"), 3.0);
+		assert_eq!(run("math.floor(2.789, 1)"), 2.7);
+		assert_eq!(run("math.ceil(2.711, 1)"), 2.8);
+		assert_eq!(run("math.round(1250, -2)"), 1300.0);
+	}
+	#[test]
+	fn complexity() {
+		let parser = crate::molang::MolangParser::new();
+		let simple = parser.compile("v.x + v.y").complexity();
+		let expensive = parser.compile("math.sin(v.x) + math.pow(v.y, 2)").complexity();
+		assert_eq!(simple, 3);
+		assert!(expensive > simple);
+		assert!(parser.compile("math.random(0, 1) * 2").complexity() > parser.compile("v.x * 2").complexity());
+	}
+	#[test]
+	fn namespace_aliases() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.add_alias('m', "material");
+		assert_eq!(parser.parse("m.color = 3; return material.color + M.color;"), 6.0);
+		assert_eq!(parser.parse("m.color"), 3.0);
+		assert_eq!(parser.parse("v.m.x = 2; return v.m.x;"), 2.0);
+		assert_eq!(parser.parse("'m.color' == 'm.color'"), 1.0);
+		assert!(parser.variables_iter().any(|(name, _)| name == "material.color"));
+	}
+	#[test]
+	fn conditional_assignment() {
+		assert_eq!(run("v.x = 2; v.x ?= 9; return v.x;"), 2.0);
+		assert_eq!(run("v.y ?= 9; return v.y;"), 9.0);
+		assert_eq!(run("t.a ?= 1; t.a ?= 2; return t.a;"), 1.0);
+		assert_eq!(run("v.b ?= 1 + 2 * 3; return v.b;"), 7.0);
+	}
+	#[test]
+	fn variable_snapshots() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_global("v.speed", 2.0);
+		parser.parse("v.x = 1; v.y = 2;");
+		let snapshot = parser.snapshot_variables();
+		parser.parse("v.x = 10; v.z = 3;");
+		parser.set_global("v.speed", 5.0);
+		parser.restore_variables(&snapshot);
+		assert_eq!(parser.parse("v.x + v.y"), 3.0);
+		assert_eq!(parser.parse("v.z ?? -1"), -1.0);
+		assert_eq!(parser.parse("v.speed"), 2.0);
+	}
+	#[test]
+	fn single_argument_random() {
+		for _ in 0..100 {
+			let value = run("math.random(5)");
+			assert!((0.0..5.0).contains(&value));
+			let integer = run("math.random_integer(3)");
+			assert!([0.0, 1.0, 2.0, 3.0].contains(&integer));
+		}
+	}
+	#[test]
+	fn dead_stores() {
+		let parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.dead_stores("v.x = 1; v.x = 2; return v.x;"), vec!["variable.x"]);
+		assert!(parser.dead_stores("v.x = 1; v.x = v.x + 1; return v.x;").is_empty());
+		assert!(parser.dead_stores("v.x = 1; v.a > 0 ? {v.x = 2;}; v.y = v.x;").is_empty());
+		assert!(parser.dead_stores("t.i = 0; loop(3, {t.i = t.i + 1;}); return t.i;").is_empty());
+		assert_eq!(parser.dead_stores("t.a = 1; t.b = 1; t.a = t.b; t.b = 2; t.b = 3; t.a = 4;"), vec!["temp.a", "temp.b"]);
+		assert!(parser.dead_stores("v.x = 1; return 0; v.x = 2;").is_empty());
+	}
+	#[test]
+	fn array_reductions() {
+		assert_eq!(run("v.a = [1, 2, 3, 4]; return math.avg(v.a);"), 2.5);
+		assert_eq!(run("v.a = [1, 2, 3, 4]; return math.sum(v.a);"), 10.0);
+		assert_eq!(run("math.max_of([3, -1, 7, 2])"), 7.0);
+		assert_eq!(run("math.sum(5) + math.avg(2) + math.max_of(-4)"), 3.0);
+		assert_eq!(run("math.sum([]) + math.avg([]) + math.max_of([])"), 0.0);
+	}
+	#[test]
+	fn string_ordering() {
+		assert_eq!(run("'apple' < 'banana'"), 1.0);
+		assert_eq!(run("'apple' >= 'banana'"), 0.0);
+		assert_eq!(run("'b' <= 'b' && 'B' < 'a'"), 1.0);
+		assert_eq!(run("'1' < 2 || '1' > 2 || '1' == 1"), 0.0);
+		assert_eq!(run("'1' != 1"), 1.0);
+	}
+	#[test]
+	fn script_sets() {
+		let parser = crate::molang::MolangParser::new();
+		let mut scripts = std::collections::HashMap::new();
+		scripts.insert("idle_rotation".to_string(), "math.sin(v.time * 90) * 10");
+		scripts.insert("walk_offset".to_string(), "v.walk_offset = v.speed * 2; return v.walk_offset;");
+		let set = parser.compile_set(&scripts);
+		let mut variables = crate::molang_vars!{"v.time" => 1.0, "v.speed" => 3.0};
+		assert_eq!(set.eval("idle_rotation", &mut variables), Some(10.0));
+		assert_eq!(set.eval("walk_offset", &mut variables), Some(6.0));
+		assert_eq!(variables.get("variable.walk_offset"), Some(&6.0.into()));
+		assert_eq!(set.eval("missing", &mut variables), None);
+		assert_eq!(set.names().count(), 2);
+	}
+	#[test]
+	fn previous_statement_value() {
+		assert_eq!(run("10 * 2; this + 1"), 21.0);
+		assert_eq!(run("2; this * 3; return this + 1;"), 7.0);
+		assert_eq!(run("v.this = 5; this"), 0.0);
+		assert_eq!(run("v.this = 5; v.this"), 5.0);
+		assert_eq!(run("this"), 0.0);
+	}
+	#[test]
+	fn fractional_modulo() {
+		assert_eq!(run("math.mod(5.5, 2)"), 1.5);
+		assert_eq!(run("math.mod(7.5, 2.5)"), 0.0);
+		assert_eq!(run("math.mod(5.5, 0.5)"), 0.0);
+		assert_eq!(run("math.mod(1000000.25, 0.125)"), 0.0);
+		assert_eq!(run("math.mod(-5.5, 2)"), -1.5);
+	}
+	#[test]
+	fn struct_paths() {
+		assert_eq!(run("v.pos = 5; return v.pos.x;"), 0.0);
+		assert_eq!(run("v.pos.x = 3; return v.pos;"), 0.0);
+		assert_eq!(run("v.pos.x = 3; return v.pos.x.y ?? 7;"), 7.0);
+		assert_eq!(run("v.pos = 5; v.pos.x = 2; return v.pos + v.pos.x;"), 7.0);
+	}
+	#[test]
+	fn map_loop() {
+		assert_eq!(run("t.a = map_loop(3, {v.i = v.i + 1; v.i}); return t.a[0] + t.a[1] * 10 + t.a[2] * 100;"), 321.0);
+		assert_eq!(run("math.array_length(map_loop(4, {1}))"), 4.0);
+		assert_eq!(run("map_loop(3, {v.i = v.i + 1; v.i})[1]"), 2.0);
+		assert_eq!(run("math.sum(map_loop(5, {v.i = (v.i ?? 0) + 1;}))"), 0.0);
+		assert_eq!(run("map_loop("), 0.0);
+		assert_eq!(run("map_loop(3)"), 0.0);
+	}
+	#[test]
+	fn equality_epsilon() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse("0.3 + 0.6 == 0.9"), 0.0);
+		parser.equality_epsilon = 0.0001;
+		assert_eq!(parser.parse("0.3 + 0.6 == 0.9"), 1.0);
+		assert_eq!(parser.parse("0.3 + 0.6 != 0.9"), 0.0);
+		assert_eq!(parser.parse("math.equals_exact(0.3 + 0.6, 0.9)"), 0.0);
+		assert_eq!(parser.parse("math.equals_exact(math.floor(3.7), 3)"), 1.0);
+	}
+	#[test]
+	fn queries_without_parentheses() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.register_query_num("q.is_on_ground", |_| 1.0);
+		parser.register_query_num("q.arg_count", |args| args.len() as f32);
+		assert_eq!(parser.parse("q.is_on_ground"), parser.parse("q.is_on_ground()"));
+		assert_eq!(parser.parse("query.is_on_ground ? 5 : 2"), 5.0);
+		assert_eq!(parser.parse("q.arg_count + q.arg_count(1, 2)"), 2.0);
+	}
+	#[test]
+	fn min_max_skip_nan() {
+		assert_eq!(run("math.max(math.sqrt(-1), 3, 5)"), 5.0);
+		assert_eq!(run("math.min(4, math.sqrt(-1), 2, 7)"), 2.0);
+		assert_eq!(run("math.max(math.sqrt(-1), math.sqrt(-1))"), 0.0);
+		assert_eq!(run("math.min(math.sqrt(-1))"), 0.0);
+		assert_eq!(run("math.max(-3)"), -3.0);
+	}
+	#[test]
+	fn eval_all() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.eval_all("1+1; 2*3; 10"), vec![2.0, 6.0, 10.0]);
+		assert_eq!(parser.eval_all("v.x = 4; return v.x; 5"), vec![0.0, 4.0]);
+		assert_eq!(parser.eval_all("3; this * 2"), vec![3.0, 6.0]);
+		assert_eq!(parser.eval_all("7"), vec![7.0]);
+	}
+	#[test]
+	fn loop_count_evaluated_once() {
+		assert_eq!(run("v.n = 3; loop(v.n, {v.n = v.n + 1; v.c = v.c + 1}); return v.c;"), 3.0);
+		assert_eq!(run("loop((v.calls = v.calls + 1) * 0 + 4, {v.c = v.c + 1}); return v.calls * 10 + v.c;"), 14.0);
+	}
+	#[test]
+	fn saturate() {
+		assert_eq!(run("math.saturate(2)"), 1.0);
+		assert_eq!(run("math.saturate(-0.5)"), 0.0);
+		assert_eq!(run("math.saturate(0.5)"), 0.5);
+		assert_eq!(run("math.saturate(math.sqrt(-1))"), 0.0);
+	}
+	#[test]
+	fn identifier_characters() {
+		assert_eq!(run("v.bone_01 = 2; return v.bone_01;"), 2.0);
+		assert_eq!(run("temp.x_y_z = 3; return t.x_y_z;"), 3.0);
+		assert_eq!(run("v.a2e = 1; v.b = v.a2e-1; return v.b;"), 0.0);
+		// A segment that starts with a digit is a name like any other
+		assert_eq!(run("v.1x = 4; return v.1x * 2;"), 8.0);
+		assert_eq!(run("v.2e1 = 5; return v.2e1;"), 5.0);
+		assert_eq!(run("v.2e = 5; return v.2e-1;"), 4.0);
+		let mut parser = crate::molang::MolangParser::new();
+		parser.set_global("q.anim_time_2", 1.5);
+		assert_eq!(parser.parse("q.anim_time_2 + query.anim_time_2"), 3.0);
+	}
+	#[test]
+	fn malformed_identifiers() {
+		use crate::molang::MolangError;
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse("v"), 0.0);
+		assert_eq!(parser.parse("q"), 0.0);
+		assert_eq!(parser.try_parse("v"), Err(MolangError::MalformedIdentifier("v".to_string())));
+		assert_eq!(parser.try_parse("query + 1"), Err(MolangError::MalformedIdentifier("query".to_string())));
+		assert_eq!(parser.try_parse("v..x"), Err(MolangError::MalformedIdentifier("v..x".to_string())));
+		assert_eq!(parser.try_parse("v.x."), Err(MolangError::MalformedIdentifier("v.x.".to_string())));
+		assert_eq!(parser.try_parse("v.x_1 + v.ü"), Ok(0.0));
+	}
+	#[test]
+	fn undefined_behavior() {
+		use crate::molang::{MolangError, UndefinedBehavior};
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.try_parse("v.never_set + 1"), Ok(1.0));
+		parser.undefined_behavior = UndefinedBehavior::Nan;
+		assert!(parser.parse("v.never_set + 1").is_nan());
+		assert_eq!(parser.parse("v.never_set ?? 2"), 2.0);
+		parser.undefined_behavior = UndefinedBehavior::Error;
+		assert_eq!(parser.try_parse("v.never_set"), Err(MolangError::UndefinedVariable("variable.never_set".to_string())));
+		assert_eq!(parser.parse("v.never_set + 1"), 1.0);
+		assert_eq!(parser.try_parse("v.x = 3; return v.x + (v.y ?? 1);"), Ok(4.0));
+	}
+	#[test]
+	fn truthiness() {
+		assert_eq!(run("'x' && 1"), 1.0);
+		assert_eq!(run("'' || 0"), 0.0);
+		assert_eq!(run("!'' + !'a'"), 1.0);
+		assert_eq!(run("[] ? 1 : 2"), 2.0);
+		assert_eq!(run("v.list = [0]; return v.list ? 1 : 2;"), 1.0);
+		assert_eq!(run("0.5 && -1"), 1.0);
+	}
+	#[test]
+	fn closure_evaluation() {
+		let parser = crate::molang::MolangParser::new();
+		let inputs = [
+			"1 + 2 * 3", "v.x * 2 - v.y / 4", "-v.x + math.abs(v.y - 10)", "math.pow(v.x, 2) % 7",
+			"v.x < v.y ? math.sin(v.x * 90) : math.cos(v.y)", "math.clamp(v.x, 0, 1) + math.lerp(0, 10, 0.25)",
+			"math.floor(v.y / 3) + math.ceil(v.x / 3) + math.round(2.5) + math.sqrt(16)",
+			"v.s == 'a' ? 1 : 2", "v.z = v.x * 3; return v.z + 1;", "v.list[1] + math.array_length(v.list)",
+			"'b' > 'a'", "v.missing ?? 4", "v.s ? v.x : v.y"
+		];
+		for input in inputs.iter() {
+			let mut tree_variables = crate::molang_vars!{"v.x" => 2.0, "v.y" => 9.0, "v.s" => crate::molang::MolangValue::String("a".to_string()),
+				"v.list" => crate::molang::MolangValue::Array(vec![4.0, 5.0])};
+			let mut closure_variables = tree_variables.clone();
+			let script = parser.compile(input);
+			let expected = script.eval(&mut tree_variables);
+			assert_eq!(script.into_fn()(&mut closure_variables), expected, "{}", input);
+			assert_eq!(closure_variables, tree_variables, "{}", input);
+		}
+	}
+	#[test]
+	fn stateless_evaluation() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.persistent_variables = false;
+		parser.set_global("v.base", 2.0);
+		parser.parse("v.x = 5");
+		assert_eq!(parser.parse("return v.x;"), 0.0);
+		assert_eq!(parser.parse("v.y = 1; return v.y + v.base;"), 3.0);
+		assert_eq!(parser.try_parse("v.y"), Ok(0.0));
+		parser.persistent_variables = true;
+		parser.parse("v.x = 5");
+		assert_eq!(parser.parse("return v.x;"), 5.0);
+	}
+	#[test]
+	fn parse_outputs() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse_outputs("v.a=1;v.b=2", &["v.a", "v.b"]), vec![1.0, 2.0]);
+		parser.set_global("v.speed", 4.0);
+		assert_eq!(parser.parse_outputs("t.scale = v.a * 3;", &["temp.scale", "V.speed", "v.unset"]), vec![3.0, 4.0, 0.0]);
+	}
+	#[test]
+	fn string_escapes() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse(r"'a\'b' == 'a\'b'"), 1.0);
+		parser.parse(r"v.text = 'it\'s'; v.path = 'a\\b'; v.lines = 'x\ny'; v.tricky = 'a; b?c:d'");
+		let value = |name: &str| parser.variables_iter().find(|(n, _)| *n == name).map(|(_, value)| value.to_string());
+		assert_eq!(value("variable.text"), Some("'it's'".to_string()));
+		assert_eq!(value("variable.path"), Some(r"'a\b'".to_string()));
+		assert_eq!(value("variable.lines"), Some("'x\ny'".to_string()));
+		assert_eq!(value("variable.tricky"), Some("'a; b?c:d'".to_string()));
+		assert_eq!(parser.parse(r"'a\\' == 'a\\'"), 1.0);
+	}
+	#[test]
+	fn owned_input() {
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse_owned("v.x = 2; return v.x * 3;".to_string()), 6.0);
+		assert!(parser.is_cached("v.x = 2; return v.x * 3;"));
+		assert_eq!(parser.parse("v.x = 2; return v.x * 3;"), 6.0);
+		assert_eq!(parser.cache_len(), 1);
+	}
+	#[test]
+	fn malformed_loops() {
+		use crate::molang::MolangError;
+		let mut parser = crate::molang::MolangParser::new();
+		assert_eq!(parser.parse("loop("), 0.0);
+		assert_eq!(parser.parse("loop(3, q.i, {1}); return q.i;"), 0.0);
+		assert_eq!(parser.try_parse("loop(3, q.i, {1})"), Err(MolangError::InvalidAssignmentTarget("q.i".to_string())));
+		assert_eq!(parser.try_parse("loop(3, v.a[0], {1})"), Err(MolangError::InvalidAssignmentTarget("v.a[0]".to_string())));
+		assert_eq!(parser.try_parse("loop(2, t.i, {t.i}); return t.i;"), Ok(1.0));
+	}
+	#[test]
+	fn random_unbounded() {
+		assert_eq!(run("math.random(0, 1/0)"), 0.0);
+		assert_eq!(run("math.random(-3e38, 3e38)"), -3e38);
+		assert_eq!(run("math.random_integer(0, 1/0)"), 0.0);
+		assert_eq!(run("math.random_integer(1e30, 1e30)"), 1e30);
+		assert_eq!(run("math.die_roll(1, 0, 1/0)"), 0.0);
+		assert_eq!(run("math.die_roll_integer(2, 0, 1/0)"), 0.0);
+	}
+	#[test]
+	fn optional_queries() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.register_query_num("q.is_on_ground", |_| 1.0);
+		assert_eq!(parser.parse("q.is_on_ground ?? 5"), 1.0);
+		assert_eq!(parser.parse("math.is_defined(q.is_on_ground)"), 1.0);
+		assert_eq!(parser.parse("q.is_flying ?? 5"), 5.0);
+	}
+	#[test]
+	fn string_fallbacks() {
+		assert_eq!(run("v.s = 'zombie'; return (v.s ?? 'x') == 'zombie';"), 1.0);
+		assert_eq!(run("('a' ?? 'b') == 'a'"), 1.0);
+		assert_eq!(run("(v.missing ?? 'b') == 'b'"), 1.0);
+		assert_eq!(run("v.x = (true ? 'a'); return v.x == 'a';"), 1.0);
+		assert_eq!(run("v.x = (false ? 'a'); return v.x;"), 0.0);
+	}
+	#[test]
+	fn stateless_arrays() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.persistent_variables = false;
+		parser.set_array("v.points", vec![1.0, 2.0, 3.0]);
+		assert_eq!(parser.parse("v.points[2]"), 3.0);
+		assert_eq!(parser.parse("v.points[0]"), 1.0);
+	}
+	#[test]
+	fn query_context_aliases() {
+		let mut parser = crate::molang::MolangParser::new();
+		parser.add_alias('m', "material");
+		parser.set_global("material.color", 4.0);
+		parser.register_query_with_context("query.color", |context, _| context.eval("m.color * 2").into());
+		assert_eq!(parser.parse("q.color"), 8.0);
+		assert_eq!(parser.parse("q.color + 1"), 9.0);
+		parser.max_depth = 4;
+		parser.register_query_with_context("query.deep", |context, _| context.eval("1+1+1+1+1").into());
+		assert_eq!(parser.parse("q.deep"), 0.0);
+	}
+	#[test]
+	fn optional_arguments() {
+		assert_eq!(run("math.round(1.2345, 2)"), 1.23);
+		assert_eq!(run("math.floor(1.5)"), 1.0);
+		let value = run("math.random(4)");
+		assert!((0.0..4.0).contains(&value));
+		let value = run("math.random_integer(3)");
+		assert!(value == value.floor() && (0.0..=3.0).contains(&value));
+		let parser = crate::molang::MolangParser::new();
+		assert!(!parser.compile("math.random(4)").is_constant());
+	}
+	#[test]
+	fn negative_zero_side_paths() {
+		let mut parser = crate::molang::MolangParser::new();
+		let values = parser.eval_all("math.trunc(-0.5)");
+		assert!(values[0].is_sign_positive());
+		assert!(parser.parse_uncached("math.trunc(-0.5)").is_sign_positive());
+		assert!(parser.parse_debug("math.trunc(-0.5)").0.is_sign_positive());
+		assert!(parser.parse_vec3("[math.trunc(-0.5), 1, 2]")[0].is_sign_positive());
+	}
+	#[test]
+	fn map_loop_analysis() {
+		let parser = crate::molang::MolangParser::new();
+		let script = parser.compile("map_loop(3, {1})");
+		assert!(!script.is_constant());
+		assert_eq!(script.eval_const(), None);
+		assert!(script.is_pure());
+		assert!(!parser.compile("map_loop(3, {v.x = 1})").is_pure());
+	}
+	#[test]
+	fn nested_calls() {
+		let input = format!("{}1{}", "scope(".repeat(40), ")".repeat(40));
+		assert_eq!(run(&input), 1.0);
+		let input = format!("{}1{}", "loop(1, ".repeat(40), ")".repeat(40));
+		assert_eq!(run(&input), 1.0);
+		let input = format!("{}1{}", "map_loop(1, ".repeat(40), ")".repeat(40));
+		crate::molang::MolangParser::new().compile(&input);
+	}
+	#[test]
 	fn not_enough_arguments() {
 		assert_eq!(run("Math.pow()"), 1.0);
 	}
@@ -118,4 +1196,54 @@ mod tests {
 	fn broken_expression() {
 		assert_eq!(run(")22 + 5 * (v.something"), 0.0);
 	}
+	#[test]
+	fn empty_input() {
+		use crate::molang::{MolangParser, MolangError};
+		let mut parser = MolangParser::new();
+		assert_eq!(parser.try_parse(""), Err(MolangError::Empty));
+		assert_eq!(parser.try_parse("   "), Err(MolangError::Empty));
+		assert_eq!(parser.try_parse("0"), Ok(0.0));
+		assert_eq!(parser.parse(""), 0.0);
+	}
+	#[test]
+	fn contexts() {
+		use crate::molang::MolangParser;
+		let mut parser = MolangParser::new();
+		let mut context_a = parser.new_context();
+		let mut context_b = parser.new_context();
+		parser.parse_with_context("variable.x = 3", &mut context_a);
+		parser.parse_with_context("variable.x = 7", &mut context_b);
+		assert_eq!(parser.parse_with_context("variable.x", &mut context_a), 3.0);
+		assert_eq!(parser.parse_with_context("variable.x", &mut context_b), 7.0);
+		assert_eq!(parser.parse("variable.x"), 0.0);
+	}
+	#[test]
+	fn debug_tree() {
+		use crate::molang::MolangParser;
+		let mut parser = MolangParser::new();
+		let (result, tree) = parser.parse_debug("1+1*2");
+		assert_eq!(result, 3.0);
+		assert_eq!(tree, "Add\n  Number(1)\n  Multiply\n    Number(1)\n    Number(2)\n");
+	}
+	#[test]
+	fn variable_resolver() {
+		use crate::molang::MolangParser;
+		let mut parser = MolangParser::new();
+		parser.set_variable_resolver(Box::new(|name| Some(name.len() as f32)));
+		assert_eq!(parser.parse("v.abc"), 12.0);
+		assert_eq!(parser.parse("q.abc"), 9.0);
+		assert_eq!(parser.parse("t.abc"), 0.0);
+		assert_eq!(parser.parse("v.abc = 1; return v.abc;"), 1.0);
+	}
+	#[test]
+	fn constant_and_pure() {
+		use crate::molang::MolangParser;
+		let parser = MolangParser::new();
+		let constant = parser.compile("2+2");
+		assert!(constant.is_constant() && constant.is_pure());
+		let pure = parser.compile("v.x+1");
+		assert!(!pure.is_constant() && pure.is_pure());
+		let random = parser.compile("math.random(0,1)");
+		assert!(!random.is_constant() && !random.is_pure());
+	}
 }
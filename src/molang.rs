@@ -1,19 +1,36 @@
-use std::{collections::HashMap, convert::TryInto};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use regex::Regex;
 
 mod math {
 	use rand::Rng;
 
+	// Bounds can be passed in either order, so a missing second argument makes "math.random(n)" sample from 0 to n.
+	// Equal or NaN bounds return the lower bound instead of sampling an empty range
 	pub fn random(a: f32, b: f32) -> f32 {
+		let (low, high) = if a > b {(b, a)} else {(a, b)};
+		// Infinite or overflowing spans can't be sampled, this also catches NaN
+		if low == high || !(high - low).is_finite() {
+			return low;
+		}
 		let mut rng = rand::thread_rng();
-		rng.gen_range(a..b)
+		rng.gen_range(low..high)
 	}
 
 	pub fn random_int(a: f32, b: f32) -> f32 {
+		let (low, high) = if a > b {(b, a)} else {(a, b)};
+		let end = high + 1.0;
+		// At large magnitudes adding 1 can be lost, leaving an empty range
+		if end <= low || !(end - low).is_finite() {
+			return low.floor();
+		}
 		let mut rng = rand::thread_rng();
-		rng.gen_range(a..(b+1.0)).floor()
+		rng.gen_range(low..end).floor()
 	}
 
+	// The number of rolls is rounded down, and a negative number of rolls sums to 0
 	pub fn die_roll(num: f32, low: f32, high: f32) -> f32 {
 		let iterations = num.max(0.0) as i32;
 		let mut sum = 0.0;
@@ -32,10 +49,49 @@ mod math {
 		return sum;
 	}
 
+	// Unless NaN is propagated, a NaN operand is ignored and the other one is returned
+	pub fn min(a: f32, b: f32, propagate_nan: bool) -> f32 {
+		if propagate_nan && (a.is_nan() || b.is_nan()) {f32::NAN} else {a.min(b)}
+	}
+
+	pub fn max(a: f32, b: f32, propagate_nan: bool) -> f32 {
+		if propagate_nan && (a.is_nan() || b.is_nan()) {f32::NAN} else {a.max(b)}
+	}
+
+	// Turns -0 into 0, so that results never show up as "-0"
+	pub fn normalize_zero(value: f32) -> f32 {
+		if value == 0.0 {0.0} else {value}
+	}
+
+	// Rounds half to even, used wherever a result is needed as a count
+	pub fn to_int(value: f32) -> i64 {
+		value.round_ties_even() as i64
+	}
+
+	// Matches Bedrock: a NaN value returns the minimum, and swapped bounds are put in order.
+	// A NaN bound is ignored instead of panicking like `f32::clamp`
+	pub fn clamp(value: f32, min: f32, max: f32) -> f32 {
+		let (min, max) = if min > max {(max, min)} else {(min, max)};
+		if value.is_nan() {
+			return min;
+		}
+		value.max(min).min(max)
+	}
+
+	// Applies a rounding function at a number of decimal places, which is truncated to a whole number
+	pub fn round_to(value: f32, places: f32, round: fn(f32) -> f32) -> f32 {
+		let factor = 10f32.powf(places.trunc());
+		round(value * factor) / factor
+	}
+
 	pub fn lerp(start: f32, end: f32, lerp: f32) -> f32 {
 		return start + (end - start) * lerp;
 	}
 
+	pub fn lerp_clamped(start: f32, end: f32, lerp: f32) -> f32 {
+		self::lerp(start, end, clamp(lerp, 0.0, 1.0))
+	}
+
 	fn radify(num: f32) -> f32 {
 		(((num + 180.0) % 360.0) +180.0) % 360.0
 	}
@@ -57,32 +113,43 @@ mod math {
 		}
 	}
 	
-	/*pub fn in_range(value: f32, min: f32, max: f32) -> f32 {
+	pub fn in_range(value: f32, min: f32, max: f32) -> f32 {
 		if value <= max && value >= min {1.0} else {0.0}
-	}*/
+	}
 
-	/*pub fn all(value: f32, ...to_compare) {
-		return (to_compare.findIndex(c => c !== value) === -1) {1.0} else {0.0};
+	pub fn all(value: f32, to_compare: &[f32]) -> f32 {
+		if to_compare.iter().all(|c| *c == value) {1.0} else {0.0}
 	}
 
-	pub fn any(value: f32, ...to_compare) {
-		return to_compare.findIndex(c => c == value) >= 0 {1.0} else {0.0};
+	pub fn any(value: f32, to_compare: &[f32]) -> f32 {
+		if to_compare.contains(&value) {1.0} else {0.0}
 	}
 
-	pub fn approx_eq(value: f32, ...to_compare) {
-		return (to_compare.findIndex(c => Math.abs(value - c) > 0.0000001) === -1) {1.0} else {0.0};
-	}*/
+	pub fn approx_eq(value: f32, to_compare: &[f32]) -> f32 {
+		if to_compare.iter().all(|c| (value - c).abs() <= 0.0000001) {1.0} else {0.0}
+	}
 }
 
 static ANGLE_FACTOR: f32 = std::f32::consts::PI / 180.0;
 
+static MATH_CONSTANTS: [(&str, f32); 3] = [
+	("pi", std::f32::consts::PI),
+	("tau", std::f32::consts::TAU),
+	("e", std::f32::consts::E),
+];
+
 lazy_static! {
-    pub static ref STRING_NUMBER_REGEX: Regex = Regex::new(r"^-?\d+(\.\d+f?)?$").unwrap();
-    pub static ref ALLOCATION_REGEX: Regex = Regex::new(r"^(temp|variable|t|v)\.\w+=").unwrap();
+    pub static ref STRING_NUMBER_REGEX: Regex = Regex::new(r"^-?\d+(\.\d+f?|(\.\d+)?e[+-]?\d+)?$").unwrap();
+    pub static ref HEX_NUMBER_REGEX: Regex = Regex::new(r"^0x[0-9a-f]+$").unwrap();
+    pub static ref ALLOCATION_REGEX: Regex = Regex::new(r"^(temp|variable|t|v)(\.\w+)+=").unwrap();
+    pub static ref CONDITIONAL_ALLOCATION_REGEX: Regex = Regex::new(r"^(temp|variable|t|v)(\.\w+)+\?=").unwrap();
+    pub static ref INDEXED_ALLOCATION_REGEX: Regex = Regex::new(r"^(temp|variable|t|v)(\.\w+)+\[[^=]*\]=").unwrap();
+    pub static ref READONLY_ALLOCATION_REGEX: Regex = Regex::new(r"^(query|context|math|q|c)(\.\w+)+\??=").unwrap();
+    pub static ref NAMESPACE_ALLOCATION_REGEX: Regex = Regex::new(r"^[a-z_]\w*(\.\w+)+=").unwrap();
 }
 
 // Operation Types
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum OperationType {
 	Add,
 	Subtract,
@@ -117,6 +184,7 @@ enum OperationType {
 	Max,
 	Clamp,
 	Lerp,
+	LerpClamped,
 	Lerprotate,
 	Asin,
 	Acos,
@@ -126,35 +194,168 @@ enum OperationType {
 	DierollInt,
 	HermiteBlend,
 	RandomInt,
+	ToRad,
+	ToDeg,
+	InRange,
+	All,
+	Any,
+	ApproxEq,
+	IsDefined,
+	ArrayLength,
+	Sum,
+	Average,
+	MaxOf,
+	IsolatedScope,
+	MapLoop,
+	EqualsExact,
+	Saturate,
 }
-// Tree Types
+
+/// A built-in function that can be called from Molang
 #[derive(Debug)]
+pub struct Builtin {
+	pub name: &'static str,
+	pub arity: usize,
+	/// How many of the `arity` arguments are required. The others are optional, like the decimal places of `math.round`
+	pub min_arity: usize,
+	/// Whether any number of arguments can be passed after the first `arity` arguments
+	pub variadic: bool,
+	operation: OperationType,
+}
+const fn builtin(name: &'static str, arity: usize, operation: OperationType) -> Builtin {
+	Builtin {name, arity, min_arity: arity, variadic: false, operation}
+}
+const fn optional(name: &'static str, min_arity: usize, arity: usize, operation: OperationType) -> Builtin {
+	Builtin {name, arity, min_arity, variadic: false, operation}
+}
+const fn variadic(name: &'static str, arity: usize, operation: OperationType) -> Builtin {
+	Builtin {name, arity, min_arity: arity, variadic: true, operation}
+}
+
+static BUILTINS: [Builtin; 40] = [
+	builtin("math.abs", 1, OperationType::Abs),
+	builtin("math.sin", 1, OperationType::Sin),
+	builtin("math.cos", 1, OperationType::Cos),
+	builtin("math.exp", 1, OperationType::Exp),
+	builtin("math.ln", 1, OperationType::Ln),
+	builtin("math.pow", 2, OperationType::Pow),
+	builtin("math.sqrt", 1, OperationType::Sqrt),
+	optional("math.random", 1, 2, OperationType::Random),
+	optional("math.ceil", 1, 2, OperationType::Ceil),
+	optional("math.round", 1, 2, OperationType::Round),
+	builtin("math.trunc", 1, OperationType::Trunc),
+	optional("math.floor", 1, 2, OperationType::Floor),
+	builtin("math.mod", 2, OperationType::Modulo),
+	variadic("math.min", 2, OperationType::Min),
+	variadic("math.max", 2, OperationType::Max),
+	builtin("math.clamp", 3, OperationType::Clamp),
+	builtin("math.saturate", 1, OperationType::Saturate),
+	builtin("math.lerp", 3, OperationType::Lerp),
+	builtin("math.lerpc", 3, OperationType::LerpClamped),
+	builtin("math.lerprotate", 3, OperationType::Lerprotate),
+	builtin("math.asin", 1, OperationType::Asin),
+	builtin("math.acos", 1, OperationType::Acos),
+	builtin("math.atan", 1, OperationType::Atan),
+	builtin("math.atan2", 2, OperationType::Atan2),
+	builtin("math.die_roll", 3, OperationType::Dieroll),
+	builtin("math.die_roll_integer", 3, OperationType::DierollInt),
+	builtin("math.hermite_blend", 1, OperationType::HermiteBlend),
+	optional("math.random_integer", 1, 2, OperationType::RandomInt),
+	builtin("math.to_rad", 1, OperationType::ToRad),
+	builtin("math.to_deg", 1, OperationType::ToDeg),
+	builtin("math.is_defined", 1, OperationType::IsDefined),
+	builtin("math.array_length", 1, OperationType::ArrayLength),
+	builtin("math.sum", 1, OperationType::Sum),
+	builtin("math.avg", 1, OperationType::Average),
+	builtin("math.max_of", 1, OperationType::MaxOf),
+	builtin("math.equals_exact", 2, OperationType::EqualsExact),
+	builtin("query.in_range", 3, OperationType::InRange),
+	variadic("query.all", 2, OperationType::All),
+	variadic("query.any", 2, OperationType::Any),
+	variadic("query.approx_eq", 2, OperationType::ApproxEq),
+];
+
+// Tree Types
+#[derive(Debug, Clone)]
 enum Expression {
 	Number(f32),
-	//String(String),
+	String(String),
 	Operation1(OperationType, Box<Expression>),
 	Operation2(OperationType, Box<Expression>, Box<Expression>),
 	Operation3(OperationType, Box<Expression>, Box<Expression>, Box<Expression>),
-	Variable(String),
-	//QueryFunction(String),
-	Allocation(String, Box<Expression>),
+	OperationN(OperationType, Vec<Expression>),
+	// Variables are stored with their slot, the position of the name in the variable list of the compiled expression.
+	// The parser leaves it at 0, and `CompiledMolang::new` assigns it so `eval_slice` can index directly
+	Variable(String, usize),
+	QueryFunction(String, Vec<Expression>),
+	Allocation(String, usize, Box<Expression>),
 	ReturnStatement(Box<Expression>),
-	Loop(Box<Expression>, Box<Expression>),
-	Scope(Vec<Expression>)
+	// Count, optional variable and slot that the 0-based iteration index is written to, and body
+	Loop(Box<Expression>, Option<(String, usize)>, Box<Expression>),
+	Scope(Vec<Expression>),
+	Array(Vec<Expression>),
+	Index(Box<Expression>, Box<Expression>),
+	// The value of the previous statement in the enclosing scope
+	This,
+	// Input that couldn't be parsed. Its parts are still evaluated for their side effects, and it evaluates to 0
+	Invalid(MolangError, Vec<Expression>)
 }
 
-fn create_operation_1(op_type: OperationType, s1: &str) -> Expression {
-	Expression::Operation1(op_type, Box::new(parse_string_slice(s1)))
+// The depth is the number of levels that the operands may still be nested
+fn create_operation_1(op_type: OperationType, s1: &str, depth: usize) -> Expression {
+	Expression::Operation1(op_type, Box::new(parse_string_slice(s1, depth)))
 }
-fn create_operation_2(op_type: OperationType, s1: &str, s2: &str) -> Expression {
-	Expression::Operation2(op_type, Box::new(parse_string_slice(s1)), Box::new(parse_string_slice(s2)))
+fn create_operation_2(op_type: OperationType, s1: &str, s2: &str, depth: usize) -> Expression {
+	Expression::Operation2(op_type, Box::new(parse_string_slice(s1, depth)), Box::new(parse_string_slice(s2, depth)))
 }
-fn create_operation_3(op_type: OperationType, s1: &str, s2: &str, s3: &str) -> Expression {
-	Expression::Operation3(op_type, Box::new(parse_string_slice(s1)), Box::new(parse_string_slice(s2)), Box::new(parse_string_slice(s3)))
+fn create_operation_3(op_type: OperationType, s1: &str, s2: &str, s3: &str, depth: usize) -> Expression {
+	Expression::Operation3(op_type, Box::new(parse_string_slice(s1, depth)), Box::new(parse_string_slice(s2, depth)), Box::new(parse_string_slice(s3, depth)))
+}
+
+fn create_builtin(builtin: &Builtin, params: &[&str], depth: usize) -> Expression {
+	let param = |i: usize| params.get(i).copied().unwrap_or("");
+	if builtin.variadic {
+		return Expression::OperationN(builtin.operation, params.iter().map(|p| parse_string_slice(p, depth)).collect());
+	}
+	// Optional arguments that are passed select the longer form of the operation, missing required ones evaluate to 0
+	match params.len().clamp(builtin.min_arity, builtin.arity) {
+		1 => create_operation_1(builtin.operation, param(0), depth),
+		2 => create_operation_2(builtin.operation, param(0), param(1), depth),
+		_ => create_operation_3(builtin.operation, param(0), param(1), param(2), depth),
+	}
+}
+
+/// Expands a name to the full form that is used in variable maps, like `variable.x` for `V.x`
+pub fn normalize_name(name: &str) -> String {
+	to_variable_name(&name.to_lowercase())
+}
+
+/// Builds a variable map for `CompiledMolang::eval`, with names like `v.x` expanded to their full form
+#[macro_export]
+macro_rules! molang_vars {
+	($($name:expr => $value:expr),* $(,)?) => {{
+		#[allow(unused_mut)]
+		let mut variables = std::collections::HashMap::new();
+		$(variables.insert($crate::molang::normalize_name($name), $crate::molang::MolangValue::from($value));)*
+		variables
+	}};
+}
+
+// Names are made of non-empty segments of letters, digits and underscores. A namespace on its own, like "v", isn't a name
+fn is_valid_name(name: &str) -> bool {
+	let valid_segments = name.split('.').all(|segment| {
+		!segment.is_empty() && segment.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+	});
+	valid_segments && !matches!(name, "v" | "t" | "q" | "c" | "variable" | "temp" | "query" | "context")
+}
+
+// Whether a name can be written to: a valid name with members, outside of the read-only namespaces
+fn is_assignable(name: &str) -> bool {
+	is_valid_name(name) && name.contains('.') && !READONLY_ALLOCATION_REGEX.is_match(&format!("{}=", name))
 }
 
 fn to_variable_name(input: &str) -> String {
-	if &input[1..2] == "." {
+	if input.as_bytes().get(1) == Some(&b'.') {
 		let char = &input[0..1];
 		match char {
 			"q" => {return "query".to_owned() + &input[1..]},
@@ -173,16 +374,19 @@ fn split_string<'a>(s: &'a str, c: &str) -> Option<(&'a str, &'a str)> {
     if !s.contains(c) {
         return None;
     }
-    let mut level: i8 = 0;
+    let mut level: i32 = 0;
+    let mut in_string = false;
     for (i, ch) in s.char_indices() {
-        if ch == '(' || ch == '{' {
+        if ch == '\'' && is_quote(s, i) {
+            in_string = !in_string;
+        } else if in_string {
+            continue;
+        } else if ch == '(' || ch == '{' || ch == '[' {
             level += 1;
-        } else if ch == ')' || ch == '}' {
+        } else if ch == ')' || ch == '}' || ch == ']' {
             level -= 1;
-        } else if level == 0 && c.starts_with(ch) {
-            if c.len() == 1 || &s[i..i+c.len()] == c {
-                return Some((&s[..i], &s[i+c.len()..]));
-            }
+        } else if level == 0 && s[i..].starts_with(c) {
+            return Some((&s[..i], &s[i+c.len()..]));
         }
     }
     None
@@ -192,44 +396,99 @@ fn split_string_reverse<'a>(s: &'a str, c: &str) -> Option<(&'a str, &'a str)> {
     if !s.contains(c) {
         return None;
     }
-    let mut level: i8 = 0;
-    for i in (0..s.len()).rev() {
-        let ch = s.chars().nth(i).unwrap();
-        if ch == '(' || ch == '{' {
+    let mut level: i32 = 0;
+    let mut in_string = false;
+    for (i, ch) in s.char_indices().rev() {
+        if ch == '\'' && is_quote(s, i) {
+            in_string = !in_string;
+        } else if in_string {
+            continue;
+        } else if ch == '(' || ch == '{' || ch == '[' {
             level -= 1;
-        } else if ch == ')' || ch == '}' {
+        } else if ch == ')' || ch == '}' || ch == ']' {
             level += 1;
-        } else if level == 0 && c.starts_with(ch) {
-            if c.len() == 1 || &s[i..i+c.len()] == c {
-				if c != "-" || i == 0 || "+*/<>=|&?:".contains(s.chars().nth(i).unwrap_or(' ')) {
-					return Some((&s[..i], &s[i+c.len()..]));
-				}
-            }
+        } else if level == 0 && s[i..].starts_with(c) {
+			// A sign directly after another operator is unary, like in "2*-1", so keep looking for the binary operator
+			if (c == "-" || c == "+") && s[..i].ends_with(|prev: char| "+-*/%^<>=|&?:!".contains(prev)) {
+				continue;
+			}
+			// The sign of an exponent, like in "1e-10"
+			if (c == "-" || c == "+") && is_exponent_end(&s[..i]) {
+				continue;
+			}
+			return Some((&s[..i], &s[i+c.len()..]));
         }
     }
     None
 }
+// Splits the branches of a ternary at its own colon, skipping over the colons of nested ternaries
+fn split_ternary_branches(s: &str) -> Option<(&str, &str)> {
+	let mut level: i32 = 0;
+	let mut nested_ternaries = 0;
+	let mut in_string = false;
+	for (i, ch) in s.char_indices() {
+		match ch {
+			'\'' if is_quote(s, i) => {in_string = !in_string},
+			_ if in_string => {},
+			'('|'{'|'[' => {level += 1},
+			')'|'}'|']' => {level -= 1},
+			'?' if level == 0 && !s[i+1..].starts_with('?') && !s[..i].ends_with('?') => {
+				nested_ternaries += 1;
+			},
+			':' if level == 0 => {
+				if nested_ternaries == 0 {
+					return Some((&s[..i], &s[i+1..]));
+				}
+				nested_ternaries -= 1;
+			},
+			_ => {}
+		}
+	}
+	None
+}
+// Splits at the last top level occurrence of any of the operators, longer operators need to be listed first
+fn split_string_reverse_any<'a, 'b>(s: &'a str, operators: &[&'b str]) -> Option<(&'a str, &'b str, &'a str)> {
+	let mut level: i32 = 0;
+	let mut in_string = false;
+	for (i, ch) in s.char_indices().rev() {
+		match ch {
+			'\'' if is_quote(s, i) => {in_string = !in_string},
+			_ if in_string => {},
+			'('|'{'|'[' => {level -= 1},
+			')'|'}'|']' => {level += 1},
+			_ => {
+				if level == 0 {
+					if let Some(operator) = operators.iter().find(|operator| s[i..].starts_with(*operator)) {
+						return Some((&s[..i], operator, &s[i+operator.len()..]));
+					}
+				}
+			}
+		}
+	}
+	None
+}
 fn split_string_multiple<'a>(s: &'a str, c: &str) -> Vec<&'a str> {
     if !s.contains(c) {
         return vec![s];
     }
 	let c_len = c.len();
 	let mut pieces = Vec::new();
-    let mut level: i8 = 0;
+    let mut level: i32 = 0;
 	let mut last_split = 0;
+	let mut in_string = false;
 
     for (i, ch) in s.char_indices() {
 		match ch {
-			'('|'{' => {level += 1},
-			')'|'}' => {level -= 1},
+			'\'' if is_quote(s, i) => {in_string = !in_string},
+			_ if in_string => {},
+			'('|'{'|'[' => {level += 1},
+			')'|'}'|']' => {level -= 1},
 			_ => {
-				if level == 0 && c.starts_with(ch) {
-					if c_len == 1 || &s[i..i+c_len] == c {
-						let piece = &s[last_split..i];
-						pieces.push(piece);
-						last_split = i + c_len;
-						if s[last_split..].contains(c) == false {break;}
-					}
+				if level == 0 && i >= last_split && s[i..].starts_with(c) {
+					let piece = &s[last_split..i];
+					pieces.push(piece);
+					last_split = i + c_len;
+					if s[last_split..].contains(c) == false {break;}
 				}
 			}
 		}
@@ -238,44 +497,125 @@ fn split_string_multiple<'a>(s: &'a str, c: &str) -> Vec<&'a str> {
 	pieces
 	
 }
-fn compare_values(a: &Expression, b: &Expression, variables: &mut HashMap<String, f32>) -> bool {
-	let result_a = a.eval(variables);
-	let result_b = b.eval(variables);
-	//if (!(typeof a == 'string' && a[0] == `'`)) a = eval(a, true);
-	//if (!(typeof b == 'string' && b[0] == `'`)) b = eval(b, true);
-	return result_a == result_b;
+// The text between the parentheses of a call like "name(...)", if the input is one
+fn call_arguments<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+	s.strip_prefix(name)?.strip_prefix('(')?.strip_suffix(')')
+}
+// Finds the "[" that belongs to the "]" at the given position
+fn matching_bracket(s: &str, end: usize) -> Option<usize> {
+	let mut level: i32 = 0;
+	for (i, ch) in s[..=end].char_indices().rev() {
+		match ch {
+			']' => {level += 1},
+			'[' => {
+				level -= 1;
+				if level == 0 {return Some(i);}
+			},
+			_ => {}
+		}
+	}
+	None
+}
+// Strings are compared by their text, everything else by value
+// Numbers are equal if they differ by at most the epsilon, which is 0 unless configured
+fn compare_values(a: &Expression, b: &Expression, state: &mut EvalState, epsilon: f32) -> bool {
+	let result_a = a.eval_value(state);
+	let result_b = b.eval_value(state);
+	match (&result_a, &result_b) {
+		(MolangValue::String(_), _) | (_, MolangValue::String(_)) => result_a == result_b,
+		_ if epsilon > 0.0 => (result_a.as_number() - result_b.as_number()).abs() <= epsilon,
+		_ => result_a.as_number() == result_b.as_number()
+	}
+}
+// Strings are ordered lexicographically. A string and a number, or NaN, have no order, so every comparison between them is false
+fn order_values(a: &Expression, b: &Expression, state: &mut EvalState) -> Option<std::cmp::Ordering> {
+	match (a.eval_value(state), b.eval_value(state)) {
+		(MolangValue::String(a), MolangValue::String(b)) => Some(a.cmp(&b)),
+		(MolangValue::String(_), _) | (_, MolangValue::String(_)) => None,
+		(a, b) => a.as_number().partial_cmp(&b.as_number())
+	}
+}
+// Whether the character at the byte index is a quote that starts or ends a string, and not an escaped one like in 'it\'s'
+fn is_quote(s: &str, i: usize) -> bool {
+	let backslashes = s[..i].bytes().rev().take_while(|byte| *byte == b'\\').count();
+	s.as_bytes()[i] == b'\'' && backslashes % 2 == 0
+}
+fn is_string_literal(s: &str) -> bool {
+	let closing_quote = s.char_indices().skip(1).find(|(i, ch)| *ch == '\'' && is_quote(s, *i)).map(|(i, _)| i);
+	s.starts_with('\'') && closing_quote == Some(s.len() - 1)
+}
+// Resolves the escape sequences \', \\ and \n in the text of a string literal
+fn unescape(s: &str) -> String {
+	let mut output = String::with_capacity(s.len());
+	let mut chars = s.chars();
+	while let Some(ch) = chars.next() {
+		match ch {
+			'\\' => match chars.next() {
+				Some('n') => output.push('\n'),
+				Some(escaped) => output.push(escaped),
+				None => output.push('\\')
+			},
+			_ => output.push(ch)
+		}
+	}
+	output
+}
+
+// Whether the string ends with the "e" of a number in scientific notation, like "1.5e"
+fn is_exponent_end(s: &str) -> bool {
+	match s.strip_suffix('e') {
+		Some(mantissa) => {
+			let start = mantissa.char_indices().rev()
+				.find(|(_, ch)| !ch.is_ascii_digit() && *ch != '.')
+				.map_or(0, |(i, ch)| i + ch.len_utf8());
+			// Digits that are part of a name or hex number, like "v.a2e" or "0x1e", don't start a number
+			let in_identifier = mantissa[..start].ends_with(|ch: char| ch.is_alphanumeric() || ch == '_');
+			mantissa[start..].starts_with(|ch: char| ch.is_ascii_digit()) && !in_identifier
+		},
+		None => false
+	}
 }
 
 fn is_string_number(s: &str) -> bool {
 	STRING_NUMBER_REGEX.is_match(s)
 }
-
-fn can_trim_brackets(s: &str) -> bool {
-	if (s.starts_with('(') && s.ends_with(')')) || (s.starts_with('{') && s.ends_with('}')) {
-		let mut level: i8 = 1;
-		for c in s[1..s.len()-1].chars() {
-			match c {
-				'('|'{' => level += 1,
-				')'|'}' => level -= 1,
-				_ => {}
-			}
-			if level == 0 {
-				return false;
-			}
-		}
-		return true;
+// Parses a number literal, including the optional "f" suffix like in "0.2f"
+fn parse_number(s: &str) -> Option<f32> {
+	if !is_string_number(s) {
+		return None;
 	}
-	false
+	s.trim_end_matches('f').parse().ok()
 }
+
+// Removes all pairs of brackets that wrap the whole input, like in "((1+2))", in a single pass
 fn trim_brackets(input: &str) -> &str {
-	if can_trim_brackets(input) {
-		trim_brackets(&input[1..input.len()-1])
-	} else {
-		input
+	let bytes = input.as_bytes();
+	let mut layers = bytes.iter().take_while(|b| **b == b'(' || **b == b'{').count();
+	let mut open_positions = Vec::new();
+	for (i, b) in bytes.iter().enumerate() {
+		match b {
+			b'(' | b'{' => open_positions.push(i),
+			b')' | b'}' => {
+				// A leading bracket only wraps the input if it's closed by the matching trailing bracket
+				if let Some(open) = open_positions.pop() {
+					if open < layers && open + i != bytes.len() - 1 {
+						layers = open;
+					}
+				}
+			},
+			_ => {}
+		}
 	}
+	if let Some(open) = open_positions.first() {
+		layers = layers.min(*open);
+	}
+	&input[layers..input.len()-layers]
 }
 
-fn parse_string_slice(input: &str) -> Expression {
+fn parse_string_slice(input: &str, depth: usize) -> Expression {
+	if depth == 0 {
+		return Expression::Invalid(MolangError::DepthExceeded, vec![]);
+	}
 	if input.len() == 0 {
 		return Expression::Number(0.0);
 	}
@@ -286,32 +626,51 @@ fn parse_string_slice(input: &str) -> Expression {
 	};
 
 	let s = trim_brackets(&trimmed_input);
+	if s.is_empty() {
+		return Expression::Number(0.0);
+	}
 
-	if is_string_number(s) {
-		let value = s.replace('f', "").parse().unwrap();
+	if let Some(value) = parse_number(s) {
 		return Expression::Number(value);
 	}
+	if HEX_NUMBER_REGEX.is_match(s) {
+		if let Ok(value) = u64::from_str_radix(&s[2..], 16) {
+			return Expression::Number(value as f32);
+		}
+	}
+	if is_string_literal(s) {
+		return Expression::String(unescape(&s[1..s.len()-1]));
+	}
 
 	let lines = split_string_multiple(s, ";");
 	if lines.len() > 1 {
 		let mut expressions = Vec::new();
 		for line in lines.iter() {
-			let exp = parse_string_slice(&line);
+			if line.is_empty() {continue;}
+			let exp = parse_string_slice(&line, depth - 1);
 			let is_return = matches!(exp, Expression::ReturnStatement(_));
 			expressions.push(exp);
 			if is_return {break;}
 		}
 		return Expression::Scope(expressions);
 	}
+	// Comma sequence in parentheses, like "(v.x = 1, v.x + 1)", evaluates every part and returns the last one
+	if trimmed_input.starts_with('(') && s.len() < trimmed_input.len() {
+		let parts = split_string_multiple(s, ",");
+		if parts.len() > 1 {
+			return Expression::Scope(parts.iter().map(|part| parse_string_slice(part, depth - 1)).collect());
+		}
+	}
 
 	//Statement
 	if s.starts_with("return") {
-		return Expression::ReturnStatement(Box::new(parse_string_slice(&s[6..])));
+		return Expression::ReturnStatement(Box::new(parse_string_slice(&s[6..], depth - 1)));
 	}
 
 	match s {
 		"true" => {return Expression::Number(1.0)},
 		"false" => {return Expression::Number(0.0)},
+		"this" => {return Expression::This},
 		//"break" => {return Expression::Break()},
 		//"continue" => {return Expression::Continue()},
 		_ => {}
@@ -321,25 +680,47 @@ fn parse_string_slice(input: &str) -> Expression {
 
 
 	//allocation
-	if has_equal_sign && s.len() > 4 {
+	if has_equal_sign {
+		// "v.x ?= 5" only assigns if the variable isn't set, like "v.x = v.x ?? 5"
+		if let Some(result) = CONDITIONAL_ALLOCATION_REGEX.find(s) {
+			let name = to_variable_name(&s[..result.end() - 2]);
+			let fallback = Box::new(parse_string_slice(&s[result.end()..], depth - 1));
+			let value = Expression::Operation2(OperationType::NullCoalescing, Box::new(Expression::Variable(name.clone(), 0)), fallback);
+			return Expression::Allocation(name, 0, Box::new(value));
+		}
 		let mat = ALLOCATION_REGEX.find(s);
 		match mat {
 			Some(result) => {
-
-				if &s[result.end()..result.end() + 1] != "=" {
+				// A second equal sign means this is a comparison ("v.x==1"), not an assignment
+				if !s[result.end()..].starts_with('=') {
 					let name = &s[..result.end() - 1];
 					let value = &s[result.end()..];
-					return Expression::Allocation(to_variable_name(name), Box::new(parse_string_slice(&value)));
+					return Expression::Allocation(to_variable_name(name), 0, Box::new(parse_string_slice(&value, depth - 1)));
 				}
 			},
 			None => ()
 		}
+		// Assigning to an index isn't supported, and queries, context and math can't be written to, not even with "?="
+		if let Some(result) = INDEXED_ALLOCATION_REGEX.find(s).or_else(|| READONLY_ALLOCATION_REGEX.find(s)) {
+			if !s[result.end()..].starts_with('=') {
+				let target = s[..result.end() - 1].trim_end_matches('?').to_string();
+				let value = parse_string_slice(&s[result.end()..], depth - 1);
+				return Expression::Invalid(MolangError::InvalidAssignmentTarget(target), vec![value]);
+			}
+		}
+		// Other namespaces, like the ones added with `MolangParser::add_alias`, can be written to like variables
+		if let Some(result) = NAMESPACE_ALLOCATION_REGEX.find(s) {
+			if !s[result.end()..].starts_with('=') {
+				let name = &s[..result.end() - 1];
+				return Expression::Allocation(name.to_string(), 0, Box::new(parse_string_slice(&s[result.end()..], depth - 1)));
+			}
+		}
 	}
 
 	// Null Coalescing
 	match split_string(s, "??") {
 		Some(result) => {
-			return create_operation_2(OperationType::NullCoalescing, result.0, result.1);
+			return create_operation_2(OperationType::NullCoalescing, result.0, result.1, depth - 1);
 		},
 		None => ()
 	}
@@ -347,12 +728,12 @@ fn parse_string_slice(input: &str) -> Expression {
 	//ternary
 	match split_string(s, "?") {
 		Some(result) => {
-			match split_string(result.1, ":") {
+			match split_ternary_branches(result.1) {
 				Some(result2) => {
-					return create_operation_3(OperationType::Ternary, result.0, result2.0, result2.1);
+					return create_operation_3(OperationType::Ternary, result.0, result2.0, result2.1, depth - 1);
 				},
 				None => {
-					return create_operation_2(OperationType::Ternary, result.0, result.1);
+					return create_operation_2(OperationType::Ternary, result.0, result.1, depth - 1);
 				}
 			}
 		},
@@ -360,136 +741,135 @@ fn parse_string_slice(input: &str) -> Expression {
 	}
 
 	//2 part operators
-	match split_string(s, "&&") {
-		Some(result) => { return create_operation_2(OperationType::And, result.0, result.1); },
-		None => ()
-	}
+	// "||" binds weaker than "&&", so it has to be split first
 	match split_string(s, "||") {
-		Some(result) => { return create_operation_2(OperationType::Or, result.0, result.1); },
+		Some(result) => { return create_operation_2(OperationType::Or, result.0, result.1, depth - 1); },
 		None => ()
 	}
-	if has_equal_sign {
-		match split_string(s, "==") {
-			Some(result) => { return create_operation_2(OperationType::Equal, result.0, result.1); },
-			None => ()
-		}
-		match split_string(s, "!=") {
-			Some(result) => { return create_operation_2(OperationType::Unequal, result.0, result.1); },
-			None => ()
-		}
-		match split_string(s, "<=") {
-			Some(result) => { return create_operation_2(OperationType::SmallerEqual, result.0, result.1); },
-			None => ()
-		}
-	}
-	match split_string(s, "<") {
-		Some(result) => { return create_operation_2(OperationType::Smaller, result.0, result.1); },
+	match split_string(s, "&&") {
+		Some(result) => { return create_operation_2(OperationType::And, result.0, result.1, depth - 1); },
 		None => ()
 	}
+	// Comparisons are left-associative, so "1<2<3" evaluates as "(1<2)<3"
 	if has_equal_sign {
-		match split_string(s, ">=") {
-			Some(result) => { return create_operation_2(OperationType::LargerEqual, result.0, result.1); },
+		match split_string_reverse_any(s, &["==", "!="]) {
+			Some((a, "==", b)) => { return create_operation_2(OperationType::Equal, a, b, depth - 1); },
+			Some((a, _, b)) => { return create_operation_2(OperationType::Unequal, a, b, depth - 1); },
 			None => ()
 		}
 	}
-	match split_string(s, ">") {
-		Some(result) => { return create_operation_2(OperationType::Larger, result.0, result.1); },
+	match split_string_reverse_any(s, &["<=", ">=", "<", ">"]) {
+		Some((a, "<=", b)) => { return create_operation_2(OperationType::SmallerEqual, a, b, depth - 1); },
+		Some((a, ">=", b)) => { return create_operation_2(OperationType::LargerEqual, a, b, depth - 1); },
+		Some((a, "<", b)) => { return create_operation_2(OperationType::Smaller, a, b, depth - 1); },
+		Some((a, _, b)) => { return create_operation_2(OperationType::Larger, a, b, depth - 1); },
 		None => ()
 	}
 
 	match split_string_reverse(s, "+") {
-		Some(result) => { return create_operation_2(OperationType::Add, result.0, result.1); },
+		// Unary plus doesn't change the value
+		Some(("", result)) => { return parse_string_slice(result, depth - 1); },
+		Some(result) => { return create_operation_2(OperationType::Add, result.0, result.1, depth - 1); },
 		None => ()
 	}
 	match split_string_reverse(s, "-") {
 		Some(result) => {
 			if result.0.len() == 0 {
-				return create_operation_1(OperationType::Invert, result.1);
+				return create_operation_1(OperationType::Invert, result.1, depth - 1);
 			} else {
-				return create_operation_2(OperationType::Subtract, result.0, result.1);
+				return create_operation_2(OperationType::Subtract, result.0, result.1, depth - 1);
 			}
 		},
 		None => ()
 	}
-	match split_string(s, "*") {
-		Some(result) => { return create_operation_2(OperationType::Multiply, result.0, result.1); },
+	// "*", "/" and "%" have the same precedence, so split at the last one of any of them to evaluate them left to right
+	match split_string_reverse_any(s, &["*", "/", "%"]) {
+		Some((a, "*", b)) => { return create_operation_2(OperationType::Multiply, a, b, depth - 1); },
+		Some((a, "/", b)) => { return create_operation_2(OperationType::Divide, a, b, depth - 1); },
+		Some((a, _, b)) => { return create_operation_2(OperationType::Modulo, a, b, depth - 1); },
 		None => ()
 	}
-	match split_string_reverse(s, "/") {
-		Some(result) => { return create_operation_2(OperationType::Divide, result.0, result.1); },
-		None => ()
+	// Exponents are right-associative, so split at the first "^"
+	if let Some(result) = split_string(s, "^") {
+		return create_operation_2(OperationType::Pow, result.0, result.1, depth - 1);
 	}
+	// Checked after all binary operators so that "!" binds tighter than any of them
 	if s.starts_with('!') {
-		return create_operation_1(OperationType::Negate, &s[1..]);
+		return create_operation_1(OperationType::Negate, &s[1..], depth - 1);
+	}
+
+	// Array literal, like "[1, 2, 3]"
+	if s.starts_with('[') && s.ends_with(']') && matching_bracket(s, s.len() - 1) == Some(0) {
+		let inner = &s[1..s.len()-1];
+		if inner.is_empty() {
+			return Expression::Array(vec![]);
+		}
+		return Expression::Array(split_string_multiple(inner, ",").iter().map(|item| parse_string_slice(item, depth - 1)).collect());
+	}
+	// Index access, like "v.list[2]"
+	if s.ends_with(']') {
+		if let Some(begin) = matching_bracket(s, s.len() - 1) {
+			return Expression::Index(Box::new(parse_string_slice(&s[..begin], depth - 1)), Box::new(parse_string_slice(&s[begin+1..s.len()-1], depth - 1)));
+		}
 	}
 
 	if s.starts_with("math.") {
-		if s == "math.pi" {
-			return Expression::Number(std::f32::consts::PI);
+		if let Some((_, value)) = MATH_CONSTANTS.iter().find(|(name, _)| *name == &s[5..]) {
+			return Expression::Number(*value);
 		}
-		let arg_begin: usize = match s.find("(") {
-			Some(index) => {
-				index.try_into().unwrap()
-			},
-			None => { 1 }
-		};
-		let operator = &s[5..arg_begin];
-		let inner = &s[arg_begin+1..s.len()-1];
-
-		let params = match split_string(inner, ",") {
-			Some((s1, s2)) => {
-				match split_string(s2, ",") {
-					Some((t1, t2)) => {
-						(s1, t1, t2)
-					},
-					None => {
-						(s1, s2, "")
-					}
-				}
-			},
-			None => {
-				(inner, "", "")
+		if !s.contains('(') {
+			return Expression::Invalid(MolangError::UnknownFunction(s.to_string()), vec![]);
+		}
+	}
+
+	if let Some(arg_begin) = s.find('(') {
+		if s.ends_with(')') {
+			let name = to_variable_name(&s[..arg_begin]);
+			let params = split_string_multiple(&s[arg_begin+1..s.len()-1], ",");
+			if let Some(builtin) = BUILTINS.iter().find(|builtin| builtin.name == name) {
+				return create_builtin(builtin, &params, depth - 1);
 			}
-		};
+			// Only parsed for unknown math and query functions, since calls like scope() parse their own arguments below
+			let args = || -> Vec<Expression> {
+				params.iter().filter(|param| !param.is_empty()).map(|param| parse_string_slice(param, depth - 1)).collect()
+			};
+			if name.starts_with("math.") {
+				return Expression::Invalid(MolangError::UnknownFunction(name), args());
+			}
+			if name.starts_with("query.") {
+				return Expression::QueryFunction(name, args());
+			}
+		}
+	}
 
-		match operator {
-			"abs" => 				{return create_operation_1(OperationType::Abs, params.0)},
-			"sin" => 				{return create_operation_1(OperationType::Sin, params.0)},
-			"cos" => 				{return create_operation_1(OperationType::Cos, params.0)},
-			"exp" => 				{return create_operation_1(OperationType::Exp, params.0)},
-			"ln" => 				{return create_operation_1(OperationType::Ln, params.0)},
-			"pow" => 				{return create_operation_2(OperationType::Pow, params.0, params.1)},
-			"sqrt" => 				{return create_operation_1(OperationType::Sqrt, params.0)},
-			"random" => 			{return create_operation_2(OperationType::Random, params.0, params.1)},
-			"ceil" => 				{return create_operation_1(OperationType::Ceil, params.0)},
-			"round" => 				{return create_operation_1(OperationType::Round, params.0)},
-			"trunc" => 				{return create_operation_1(OperationType::Trunc, params.0)},
-			"floor" => 				{return create_operation_1(OperationType::Floor, params.0)},
-			"mod" => 				{return create_operation_2(OperationType::Modulo, params.0, params.1)},
-			"min" => 				{return create_operation_2(OperationType::Min, params.0, params.1)},
-			"max" => 				{return create_operation_2(OperationType::Max, params.0, params.1)},
-			"clamp" => 				{return create_operation_3(OperationType::Clamp, params.0, params.1, params.2)},
-			"lerp" => 				{return create_operation_3(OperationType::Lerp, params.0, params.1, params.2)},
-			"lerprotate" => 		{return create_operation_3(OperationType::Lerprotate, params.0, params.1, params.2)},
-			"asin" => 				{return create_operation_1(OperationType::Asin, params.0)},
-			"acos" => 				{return create_operation_1(OperationType::Acos, params.0)},
-			"atan" => 				{return create_operation_1(OperationType::Atan, params.0)},
-			"atan2" => 				{return create_operation_2(OperationType::Atan2, params.0, params.1)},
-			"die_roll" => 			{return create_operation_3(OperationType::Dieroll, params.0, params.1, params.2)},
-			"die_roll_integer" =>	{return create_operation_3(OperationType::DierollInt, params.0, params.1, params.2)},
-			"hermite_blend" => 		{return create_operation_1(OperationType::HermiteBlend, params.0)},
-			"random_integer" => 	{return create_operation_2(OperationType::RandomInt, params.0, params.1)},
-			_ => {return Expression::Number(0.0)}
-		}
-	}
-
-	if s.starts_with("loop(") {
-		let inner = &s[5..s.len()-1];
+	if let Some(inner) = call_arguments(s, "scope") {
+		return Expression::Operation1(OperationType::IsolatedScope, Box::new(parse_string_slice(inner, depth - 1)));
+	}
+
+	// Like a loop, but collects the value of each iteration into an array
+	if let Some(inner) = call_arguments(s, "map_loop") {
+		let params = split_string_multiple(inner, ",");
+		if params.len() == 2 {
+			return create_operation_2(OperationType::MapLoop, params[0], params[1], depth - 1);
+		}
+	}
+
+	if let Some(inner) = call_arguments(s, "loop") {
 		let params = split_string_multiple(inner, ",");
-		if params.len() >= 2 {
+		if params.len() >= 3 {
+			let count = parse_string_slice(params[0], depth - 1);
+			let body = parse_string_slice(params[2], depth - 1);
+			// The index is assigned, so it follows the same rules as assignments
+			if !is_assignable(params[1]) {
+				return Expression::Invalid(MolangError::InvalidAssignmentTarget(params[1].to_string()), vec![count, body]);
+			}
+			return Expression::Loop(Box::new(count), Some((to_variable_name(params[1]), 0)), Box::new(body));
+		}
+		if params.len() == 2 {
 			return Expression::Loop(
-				Box::new(parse_string_slice(params[0])),
-				Box::new(parse_string_slice(params[1]))
+				Box::new(parse_string_slice(params[0], depth - 1)),
+				None,
+				Box::new(parse_string_slice(params[1], depth - 1))
 			);
 		}
 	}
@@ -506,23 +886,318 @@ fn parse_string_slice(input: &str) -> Expression {
 		
 		return new QueryFunction(query_name, params);
 	}*/
-	return Expression::Variable(to_variable_name(&s));
+	if !is_valid_name(s) {
+		return Expression::Invalid(MolangError::MalformedIdentifier(s.to_string()), vec![]);
+	}
+	return Expression::Variable(to_variable_name(&s), 0);
 
 	//return Expression::Number(0.0);
 }
 
 
+/// A value that can be stored in a variable
+#[derive(Debug, Clone, PartialEq)]
+pub enum MolangValue {
+	Number(f32),
+	Array(Vec<f32>),
+	String(String),
+}
+impl MolangValue {
+	/// The numeric value, arrays and strings count as 0 in arithmetic
+	pub fn as_number(&self) -> f32 {
+		match self {
+			MolangValue::Number(value) => *value,
+			MolangValue::Array(_) | MolangValue::String(_) => 0.0
+		}
+	}
+	/// Whether the value counts as true in conditions, `&&`, `||` and `!`: numbers that aren't 0, and strings and arrays that aren't empty
+	pub fn is_truthy(&self) -> bool {
+		match self {
+			MolangValue::Number(value) => *value != 0.0,
+			MolangValue::Array(items) => !items.is_empty(),
+			MolangValue::String(string) => !string.is_empty()
+		}
+	}
+}
+impl std::fmt::Display for MolangValue {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			MolangValue::Number(value) => write!(f, "{}", value),
+			MolangValue::Array(items) => {
+				let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+				write!(f, "[{}]", items.join(", "))
+			},
+			MolangValue::String(string) => write!(f, "'{}'", string),
+		}
+	}
+}
+impl From<f32> for MolangValue {
+	fn from(value: f32) -> Self {
+		MolangValue::Number(value)
+	}
+}
+
+/// Computes values for `variable.*` and `query.*` names that aren't set.
+/// It receives the full name with all members, like `query.item.count` for `q.item.count`
+pub type VariableResolver = dyn Fn(&str) -> Option<f32>;
+/// A `query.*` function that is called with the values of its arguments
+pub type QueryCallback = dyn Fn(&mut QueryContext, &[MolangValue]) -> MolangValue;
+
+/// Handle to the running evaluation that is passed to queries registered with `register_query_with_context`
+pub struct QueryContext<'s, 'a> {
+	state: &'s mut EvalState<'a>
+}
+impl QueryContext<'_, '_> {
+	/// Parses and evaluates another script with the same variables, globals and queries as the running one
+	pub fn eval(&mut self, input: &str) -> f32 {
+		let script = self.state.compile(input);
+		// A return statement in the nested script must not end the outer one
+		let returning = self.state.returning;
+		let value = script.expression.eval(self.state);
+		self.state.returning = returning || self.state.budget_exceeded;
+		value
+	}
+}
+/// An expression turned into nested closures by `CompiledMolang::into_fn`
+pub type MolangFn = dyn Fn(&mut HashMap<String, MolangValue>) -> f32;
+/// Receives the name and value of every variable read and query call, with the `tracing` feature
+#[cfg(feature = "tracing")]
+pub type TraceHook = dyn Fn(&str, &MolangValue);
+
+// Where the variables of an evaluation are stored
+enum VariableStorage<'a> {
+	Map(&'a mut HashMap<String, MolangValue>),
+	// Values indexed by the position of their name in the expression's variable list
+	Slice(&'a [String], &'a mut [f32]),
+	// Only used for expressions that don't assign anything
+	ReadOnly(&'a HashMap<String, MolangValue>)
+}
+
+// State that is carried through the evaluation of an expression tree
+struct EvalState<'a> {
+	variables: VariableStorage<'a>,
+	// Consulted for variables that aren't set locally, before the resolver
+	globals: Option<&'a HashMap<String, MolangValue>>,
+	resolver: Option<&'a VariableResolver>,
+	queries: Option<&'a HashMap<String, Rc<QueryCallback>>>,
+	// Used to compile scripts that queries evaluate through their context
+	aliases: Option<&'a HashMap<char, String>>,
+	max_depth: usize,
+	nested_scripts: Option<&'a RefCell<HashMap<String, Rc<CompiledMolang>>>>,
+	#[cfg(feature = "tracing")]
+	trace: Option<&'a TraceHook>,
+	propagate_nan: bool,
+	equality_epsilon: f32,
+	undefined_behavior: UndefinedBehavior,
+	// The first variable that was read without being set, if undefined variables are errors
+	undefined_variable: Option<String>,
+	// Number of nodes that may still be evaluated, if limited
+	budget: Option<u64>,
+	budget_exceeded: bool,
+	// Set by a return statement to unwind through enclosing loops and scopes
+	returning: bool,
+	// Result of the last statement, which is read by "this"
+	this: f32
+}
+impl<'a> EvalState<'a> {
+	fn new(variables: &'a mut HashMap<String, MolangValue>) -> Self {
+		Self::with_storage(VariableStorage::Map(variables))
+	}
+	fn with_storage(variables: VariableStorage<'a>) -> Self {
+		Self {
+			variables,
+			globals: None,
+			resolver: None,
+			queries: None,
+			aliases: None,
+			max_depth: DEFAULT_MAX_DEPTH,
+			nested_scripts: None,
+			#[cfg(feature = "tracing")]
+			trace: None,
+			propagate_nan: false,
+			equality_epsilon: 0.0,
+			undefined_behavior: UndefinedBehavior::Zero,
+			undefined_variable: None,
+			budget: None,
+			budget_exceeded: false,
+			returning: false,
+			this: 0.0
+		}
+	}
+	// Compiles a script for a query context like the parser would, reusing earlier compilations of the same input
+	fn compile(&self, input: &str) -> Rc<CompiledMolang> {
+		if let Some(script) = self.nested_scripts.and_then(|scripts| scripts.borrow().get(input).cloned()) {
+			return script;
+		}
+		let empty = HashMap::new();
+		let script = Rc::new(compile_script(input, self.aliases.unwrap_or(&empty), self.max_depth));
+		if let Some(scripts) = self.nested_scripts {
+			scripts.borrow_mut().insert(input.to_string(), script.clone());
+		}
+		script
+	}
+	// Counts an evaluated node against the budget. Once it runs out, evaluation unwinds like a return statement
+	fn step(&mut self) -> bool {
+		match &mut self.budget {
+			Some(0) => {
+				self.budget_exceeded = true;
+				self.returning = true;
+				false
+			},
+			Some(remaining) => {
+				*remaining -= 1;
+				true
+			},
+			None => true
+		}
+	}
+	// Reports a variable read or query call to the trace hook. Compiles to nothing without the `tracing` feature
+	#[cfg(feature = "tracing")]
+	fn trace(&self, name: &str, value: &MolangValue) {
+		if let Some(hook) = self.trace {
+			hook(name, value);
+		}
+	}
+	#[cfg(not(feature = "tracing"))]
+	#[inline(always)]
+	fn trace(&self, _name: &str, _value: &MolangValue) {}
+	// Looks up a variable in the local variables, then the globals, then the resolver. Slices are indexed by the slot instead
+	fn get_variable(&self, name: &str, slot: usize) -> Option<f32> {
+		if let VariableStorage::Slice(_, values) = &self.variables {
+			return values.get(slot).copied();
+		}
+		match self.lookup(name) {
+			Some(value) => Some(value.as_number()),
+			None => self.resolve(name)
+		}
+	}
+	fn get_value(&self, name: &str, slot: usize) -> Option<MolangValue> {
+		if let VariableStorage::Slice(_, _) = &self.variables {
+			return self.get_variable(name, slot).map(MolangValue::Number);
+		}
+		match self.lookup(name) {
+			Some(value) => Some(value.clone()),
+			None => self.resolve(name).map(MolangValue::Number)
+		}
+	}
+	fn lookup(&self, name: &str) -> Option<&MolangValue> {
+		let local = match &self.variables {
+			VariableStorage::Map(variables) => variables.get(name),
+			VariableStorage::ReadOnly(variables) => variables.get(name),
+			VariableStorage::Slice(_, _) => None
+		};
+		local.or_else(|| self.globals.and_then(|globals| globals.get(name)))
+	}
+	fn set_variable(&mut self, name: &str, slot: usize, value: MolangValue) {
+		match &mut self.variables {
+			VariableStorage::Map(variables) => {
+				variables.insert(name.to_string(), value);
+			},
+			// Arrays can't be stored in a slice
+			VariableStorage::Slice(_, values) => {
+				if let Some(stored) = values.get_mut(slot) {
+					*stored = value.as_number();
+				}
+			},
+			VariableStorage::ReadOnly(_) => {}
+		}
+	}
+	// Copies the `temp.*` variables and their slots, so that a scope can discard its changes to them
+	fn temp_variables(&self) -> Vec<(String, usize, MolangValue)> {
+		match &self.variables {
+			VariableStorage::Map(variables) => variables.iter()
+				.filter(|(name, _)| name.starts_with("temp."))
+				.map(|(name, value)| (name.clone(), 0, value.clone()))
+				.collect(),
+			VariableStorage::Slice(names, values) => names.iter().zip(values.iter()).enumerate()
+				.filter(|(_, (name, _))| name.starts_with("temp."))
+				.map(|(slot, (name, value))| (name.clone(), slot, MolangValue::Number(*value)))
+				.collect(),
+			VariableStorage::ReadOnly(_) => Vec::new()
+		}
+	}
+	fn restore_temp_variables(&mut self, saved: Vec<(String, usize, MolangValue)>) {
+		if let VariableStorage::Map(variables) = &mut self.variables {
+			variables.retain(|name, _| !name.starts_with("temp."));
+		}
+		for (name, slot, value) in saved {
+			self.set_variable(&name, slot, value);
+		}
+	}
+	// The value of a variable that isn't set, which depends on the configured behavior
+	fn undefined(&mut self, name: &str) -> f32 {
+		match self.undefined_behavior {
+			UndefinedBehavior::Zero => 0.0,
+			UndefinedBehavior::Nan => f32::NAN,
+			UndefinedBehavior::Error => {
+				if self.undefined_variable.is_none() {
+					self.undefined_variable = Some(name.to_string());
+				}
+				0.0
+			}
+		}
+	}
+	fn query(&self, name: &str) -> Option<&'a Rc<QueryCallback>> {
+		match self.queries {
+			Some(queries) if name.starts_with("query.") => queries.get(name),
+			_ => None
+		}
+	}
+	fn resolve(&self, name: &str) -> Option<f32> {
+		match self.resolver {
+			Some(resolver) if name.starts_with("variable.") || name.starts_with("query.") => resolver(name),
+			_ => None
+		}
+	}
+}
+
 impl Expression {
-	fn eval(&self, variables: &mut HashMap<String, f32>) -> f32 {
+	fn eval(&self, state: &mut EvalState) -> f32 {
+		if !state.step() {
+			return 0.0;
+		}
 		match self {
 			Expression::Number(num) => num.to_owned(),
-			/*Expression::String(_string) => {
-				0.0
-			},*/
+			Expression::String(_) => 0.0,
+			Expression::This => state.this,
+			Expression::Operation1(OperationType::IsDefined, a) => {
+				if a.eval_optional(state).is_some() {1.0} else {0.0}
+			},
+			// Reductions treat a number as an array with one item, and return 0 for empty arrays
+			Expression::Operation1(op @ (OperationType::Sum | OperationType::Average | OperationType::MaxOf), a) => {
+				let items = match a.eval_value(state) {
+					MolangValue::Array(items) => items,
+					value => vec![value.as_number()]
+				};
+				if items.is_empty() {
+					return 0.0;
+				}
+				match op {
+					OperationType::Sum => items.iter().sum(),
+					OperationType::Average => items.iter().sum::<f32>() / items.len() as f32,
+					_ => items.iter().copied().fold(f32::NEG_INFINITY, |max, item| math::max(max, item, state.propagate_nan))
+				}
+			},
+			Expression::Operation1(OperationType::ArrayLength, a) => {
+				match a.eval_value(state) {
+					MolangValue::Array(items) => items.len() as f32,
+					_ => 0.0
+				}
+			},
+			Expression::Operation1(OperationType::IsolatedScope, a) => {
+				let saved = state.temp_variables();
+				let value = a.eval(state);
+				state.restore_temp_variables(saved);
+				// A return statement only ends the isolated scope
+				state.returning = state.budget_exceeded;
+				value
+			},
+			Expression::Operation1(OperationType::Negate, a) => {
+				if a.eval_truthy(state) {0.0} else {1.0}
+			},
 			Expression::Operation1(o_type, a) => {
-				let a_result = a.eval(variables);
+				let a_result = a.eval(state);
 				match o_type {
-					OperationType::Negate => if a_result == 0.0 {1.0} else {0.0},
 					OperationType::Invert => -a_result,
 					OperationType::Abs => a_result.abs(),
 					OperationType::Sin => (a_result * ANGLE_FACTOR).sin(),
@@ -530,157 +1205,1288 @@ impl Expression {
 					OperationType::Exp => a_result.exp(),
 					OperationType::Ln => a_result.ln(),
 					OperationType::Sqrt => a_result.sqrt(),
+					OperationType::Saturate => math::clamp(a_result, 0.0, 1.0),
 					OperationType::Ceil => a_result.ceil(),
 					OperationType::Round => a_result.round(),
 					OperationType::Trunc => a_result.trunc(),
 					OperationType::Floor => a_result.floor(),
-					OperationType::Asin => a_result.asin() * ANGLE_FACTOR,
-					OperationType::Acos => a_result.acos() * ANGLE_FACTOR,
-					OperationType::Atan => a_result.atan() * ANGLE_FACTOR,
+					OperationType::Asin => a_result.asin() / ANGLE_FACTOR,
+					OperationType::Acos => a_result.acos() / ANGLE_FACTOR,
+					OperationType::Atan => a_result.atan() / ANGLE_FACTOR,
+					OperationType::ToRad => a_result * ANGLE_FACTOR,
+					OperationType::ToDeg => a_result / ANGLE_FACTOR,
 					OperationType::HermiteBlend => {
 						3.0 * a_result.powi(2) - 2.0 * a_result.powi(3)
 					},
+					// With a single bound, the range starts at 0
+					OperationType::Random => math::random(0.0, a_result),
+					OperationType::RandomInt => math::random_int(0.0, a_result),
 					_ => 0.0
 				}
 			},
+			// Only the taken branch is evaluated, and a ternary without an else branch evaluates to 0 if the condition is false
+			Expression::Operation2(OperationType::Ternary, a, b) => {
+				if a.eval_truthy(state) {b.eval(state)} else {0.0}
+			},
+			Expression::Operation3(OperationType::Ternary, a, b, c) => {
+				if a.eval_truthy(state) {b.eval(state)} else {c.eval(state)}
+			},
+			Expression::Operation2(op @ (OperationType::And | OperationType::Or), a, b) => {
+				let a_result = a.eval_truthy(state);
+				let b_result = b.eval_truthy(state);
+				let result = match op {
+					OperationType::And => a_result && b_result,
+					_ => a_result || b_result
+				};
+				if result {1.0} else {0.0}
+			},
+			Expression::Operation2(OperationType::NullCoalescing, a, b) => {
+				match a.eval_optional(state) {
+					Some(value) => value.as_number(),
+					None => b.eval(state)
+				}
+			},
+			Expression::Operation2(OperationType::Equal, a, b) => {
+				if compare_values(a, b, state, state.equality_epsilon) {1.0} else {0.0}
+			},
+			Expression::Operation2(OperationType::EqualsExact, a, b) => {
+				if compare_values(a, b, state, 0.0) {1.0} else {0.0}
+			},
+			Expression::Operation2(OperationType::MapLoop, _, _) => self.eval_value(state).as_number(),
+			Expression::Operation2(OperationType::Unequal, a, b) => {
+				if compare_values(a, b, state, state.equality_epsilon) {0.0} else {1.0}
+			},
+			Expression::Operation2(op @ (OperationType::Smaller | OperationType::SmallerEqual | OperationType::Larger | OperationType::LargerEqual), a, b) => {
+				let matches = match order_values(a, b, state) {
+					Some(ordering) => match op {
+						OperationType::Smaller => ordering.is_lt(),
+						OperationType::SmallerEqual => ordering.is_le(),
+						OperationType::Larger => ordering.is_gt(),
+						_ => ordering.is_ge()
+					},
+					None => false
+				};
+				if matches {1.0} else {0.0}
+			},
+			// Operands are evaluated exactly once, left to right, so side effects in arguments like "math.max(v.peak, v.x = 1)" only happen once
 			Expression::Operation2(o_type, a, b) => {
-				let a_result = a.eval(variables);
-				let b_result = b.eval(variables);
+				let a_result = a.eval(state);
+				let b_result = b.eval(state);
 				match o_type {
 					OperationType::Add => a_result + b_result,
 					OperationType::Subtract => a_result - b_result,
 					OperationType::Multiply => a_result * b_result,
 					OperationType::Divide => a_result / b_result,
-					OperationType::And => if a_result != 0.0 && b_result != 0.0 {1.0} else {0.0},
-					OperationType::Or => if a_result != 0.0 || b_result != 0.0 {1.0} else {0.0},
-					OperationType::Smaller => if a_result < b_result {1.0} else {0.0},
-					OperationType::SmallerEqual => if a_result <= b_result {1.0} else {0.0},
-					OperationType::Larger => if a_result > b_result {1.0} else {0.0},
-					OperationType::LargerEqual => if a_result >= b_result {1.0} else {0.0},
-					OperationType::Equal => if compare_values(a.as_ref(), b.as_ref(), variables) {1.0} else {0.0},
-					OperationType::Unequal => if compare_values(a.as_ref(), b.as_ref(), variables) {0.0} else {1.0},
-					OperationType::NullCoalescing => {
-						// Todo
-						0.0
-					},
 					OperationType::Pow => a_result.powf(b_result),
 					OperationType::Random => math::random(a_result, b_result),
+					// The remainder of `%` is exact, so fractional divisors like in "math.mod(5.5, 0.5)" don't accumulate error.
+					// Only operands that can't be represented exactly, like 0.1, make the result differ from decimal math
 					OperationType::Modulo => a_result % b_result,
-					OperationType::Min => a_result.min(b_result),
-					OperationType::Max => a_result.max(b_result),
-					OperationType::Atan2 => a_result.atan2(b_result) * ANGLE_FACTOR,
+					OperationType::Min => math::min(a_result, b_result, state.propagate_nan),
+					OperationType::Max => math::max(a_result, b_result, state.propagate_nan),
+					OperationType::Atan2 => a_result.atan2(b_result) / ANGLE_FACTOR,
 					OperationType::RandomInt => math::random_int(a_result, b_result),
-					OperationType::Ternary => if a_result != 0.0 {b_result} else {0.0},
+					OperationType::Round => math::round_to(a_result, b_result, f32::round),
+					OperationType::Floor => math::round_to(a_result, b_result, f32::floor),
+					OperationType::Ceil => math::round_to(a_result, b_result, f32::ceil),
 					_ => 0.0
 				}
 			},
+			// Like with two operands, each argument is evaluated exactly once
 			Expression::Operation3(o_type, a, b, c) => {
-				let a_result = a.eval(variables);
-				let b_result = b.eval(variables);
-				let c_result = c.eval(variables);
+				let a_result = a.eval(state);
+				let b_result = b.eval(state);
+				let c_result = c.eval(state);
 				match o_type {
-					OperationType::Clamp => a_result.clamp(b_result, c_result),
+					OperationType::Clamp => math::clamp(a_result, b_result, c_result),
 					OperationType::Lerp => math::lerp(a_result, b_result, c_result),
+					OperationType::LerpClamped => math::lerp_clamped(a_result, b_result, c_result),
 					OperationType::Lerprotate => math::lerp_rotate(a_result, b_result, c_result),
 					OperationType::Dieroll => math::die_roll(a_result, b_result, c_result),
 					OperationType::DierollInt => math::die_roll_int(a_result, b_result, c_result),
-					OperationType::Ternary => if a_result != 0.0 {b_result} else {c_result},
+					OperationType::InRange => math::in_range(a_result, b_result, c_result),
 					_ => 0.0
 				}
 			},
-			Expression::Variable(a) => {
-				
-				match variables.get(a) {
-					Some(value) => {
-						value.to_owned()
+			Expression::OperationN(o_type, args) => {
+				let values: Vec<f32> = args.iter().map(|arg| arg.eval(state)).collect();
+				let (value, rest) = match values.split_first() {
+					Some(split) => split,
+					None => return 0.0
+				};
+				match o_type {
+					OperationType::All => math::all(*value, rest),
+					OperationType::Any => math::any(*value, rest),
+					OperationType::ApproxEq => math::approx_eq(*value, rest),
+					// NaN arguments are skipped, so that they can mark values that don't contribute. Only NaN returns 0
+					OperationType::Min | OperationType::Max => {
+						let result = rest.iter().fold(*value, |result, item| match o_type {
+							OperationType::Min => math::min(result, *item, state.propagate_nan),
+							_ => math::max(result, *item, state.propagate_nan)
+						});
+						if result.is_nan() && !state.propagate_nan {0.0} else {result}
 					},
-					None => {
-						0.0
-					}
+					_ => 0.0
 				}
 			},
-			/*Expression::QueryFunction(a) => {
-				0.0
-			},*/
-			Expression::Allocation(a, b) => {
-				let value = b.eval(variables);
-				variables.insert(a.clone(), value);
+			// Every member path is a separate variable, so "v.pos" and "v.pos.x" don't affect each other.
+			// Reading a path that was never assigned, like a member of a number or the parent of a member, returns 0
+			Expression::Variable(a, _) if state.query(a).is_some() => self.eval_value(state).as_number(),
+			Expression::Variable(a, slot) => {
+				let value = match state.get_variable(a, *slot) {
+					Some(value) => value,
+					None => state.undefined(a)
+				};
+				state.trace(a, &MolangValue::Number(value));
+				value
+			},
+			Expression::QueryFunction(_, _) => self.eval_value(state).as_number(),
+			Expression::Allocation(a, slot, b) => {
+				let value = b.eval_value(state);
+				state.set_variable(a, *slot, value);
 				0.0
 			},
 			Expression::ReturnStatement(a) => {
-				a.eval(variables)
+				let value = a.eval(state);
+				state.returning = true;
+				value
 			},
-			Expression::Loop(count, scope) => {
-				let iterations = count.eval(variables) as i32;
+			// The count is evaluated once, so changing its variables in the body doesn't change the number of iterations
+			Expression::Loop(count, index_variable, scope) => {
+				let iterations = math::to_int(count.eval(state));
 				let mut return_value: f32 = 0.0;
-				for _i in 0..iterations {
-					return_value = scope.eval(variables);
+				for i in 0..iterations {
+					if let Some((name, slot)) = index_variable {
+						state.set_variable(name, *slot, MolangValue::Number(i as f32));
+					}
+					return_value = scope.eval(state);
+					if state.returning {break;}
 				}
 				return_value
 			},
 			Expression::Scope(lines) => {
 				let mut return_value: f32 = 0.0;
 				for line in lines.iter() {
-					return_value = line.eval(variables);
+					return_value = line.eval(state);
+					state.this = return_value;
+					if state.returning {break;}
 				}
 				return_value
-			}
-		}
-	}
-}
-
-fn create_expression_tree(string: &str) -> Expression {
+			},
+			Expression::Invalid(_, parts) => {
+				for part in parts.iter() {
+					part.eval(state);
+				}
+				0.0
+			},
+			Expression::Array(_) => 0.0,
+			Expression::Index(array, index) => {
+				let index = index.eval(state).floor();
+				match array.eval_value(state) {
+					MolangValue::Array(items) if index >= 0.0 => items.get(index as usize).copied().unwrap_or(0.0),
+					_ => 0.0
+				}
+			}
+		}
+	}
+
+	// Evaluates the expression to a value that can also be an array
+	fn eval_value(&self, state: &mut EvalState) -> MolangValue {
+		match self {
+			Expression::String(string) => MolangValue::String(string.clone()),
+			Expression::QueryFunction(name, args) => {
+				let values: Vec<MolangValue> = args.iter().map(|arg| arg.eval_value(state)).collect();
+				let value = match state.query(name) {
+					Some(query) => query(&mut QueryContext {state}, &values),
+					None => MolangValue::Number(state.resolve(name).unwrap_or(0.0))
+				};
+				state.trace(name, &value);
+				value
+			},
+			Expression::Array(items) => MolangValue::Array(items.iter().map(|item| item.eval(state)).collect()),
+			Expression::Variable(name, slot) => {
+				// Registered queries can be used without parentheses, like "q.is_on_ground", and are called without arguments
+				let value = match state.query(name) {
+					Some(query) => query(&mut QueryContext {state}, &[]),
+					None => match state.get_value(name, *slot) {
+						Some(value) => value,
+						None => MolangValue::Number(state.undefined(name))
+					}
+				};
+				state.trace(name, &value);
+				value
+			},
+			Expression::Operation2(OperationType::Ternary, a, b) => {
+				if a.eval_truthy(state) {b.eval_value(state)} else {MolangValue::Number(0.0)}
+			},
+			Expression::Operation3(OperationType::Ternary, a, b, c) => {
+				if a.eval_truthy(state) {b.eval_value(state)} else {c.eval_value(state)}
+			},
+			Expression::Operation2(OperationType::NullCoalescing, a, b) => {
+				match a.eval_optional(state) {
+					Some(value) => value,
+					None => b.eval_value(state)
+				}
+			},
+			Expression::Operation2(OperationType::MapLoop, count, body) => {
+				let iterations = math::to_int(count.eval(state));
+				let mut items = Vec::new();
+				for _ in 0..iterations {
+					items.push(body.eval(state));
+					if state.returning {break;}
+				}
+				MolangValue::Array(items)
+			},
+			Expression::ReturnStatement(a) => {
+				let value = a.eval_value(state);
+				state.returning = true;
+				value
+			},
+			Expression::Scope(lines) => {
+				let mut return_value = MolangValue::Number(0.0);
+				for line in lines.iter() {
+					return_value = line.eval_value(state);
+					state.this = return_value.as_number();
+					if state.returning {break;}
+				}
+				return_value
+			},
+			_ => MolangValue::Number(self.eval(state))
+		}
+	}
+
+	// Evaluates the expression as a condition, where strings and arrays are true if they aren't empty
+	fn eval_truthy(&self, state: &mut EvalState) -> bool {
+		self.eval_value(state).is_truthy()
+	}
+
+	// Evaluates the expression, but returns None if it is a variable that isn't set and isn't a registered query
+	fn eval_optional(&self, state: &mut EvalState) -> Option<MolangValue> {
+		match self {
+			Expression::Variable(name, slot) if state.query(name).is_none() => state.get_value(name, *slot),
+			_ => Some(self.eval_value(state))
+		}
+	}
+
+	// Calls the visitor on this node and all of its descendants
+	fn visit(&self, visitor: &mut dyn FnMut(&Expression)) {
+		visitor(self);
+		match self {
+			Expression::Number(_) | Expression::Variable(_, _) | Expression::String(_) | Expression::This => {},
+			Expression::Operation1(_, a) | Expression::Allocation(_, _, a) | Expression::ReturnStatement(a) => {
+				a.visit(visitor);
+			},
+			Expression::Operation2(_, a, b) | Expression::Loop(a, _, b) | Expression::Index(a, b) => {
+				a.visit(visitor);
+				b.visit(visitor);
+			},
+			Expression::Operation3(_, a, b, c) => {
+				a.visit(visitor);
+				b.visit(visitor);
+				c.visit(visitor);
+			},
+			Expression::OperationN(_, lines) | Expression::Scope(lines) | Expression::Invalid(_, lines) | Expression::Array(lines) | Expression::QueryFunction(_, lines) => {
+				for line in lines.iter() {
+					line.visit(visitor);
+				}
+			}
+		}
+	}
+
+	// Like `visit`, but the visitor can change the nodes
+	fn visit_mut(&mut self, visitor: &mut dyn FnMut(&mut Expression)) {
+		visitor(self);
+		match self {
+			Expression::Number(_) | Expression::Variable(_, _) | Expression::String(_) | Expression::This => {},
+			Expression::Operation1(_, a) | Expression::Allocation(_, _, a) | Expression::ReturnStatement(a) => {
+				a.visit_mut(visitor);
+			},
+			Expression::Operation2(_, a, b) | Expression::Loop(a, _, b) | Expression::Index(a, b) => {
+				a.visit_mut(visitor);
+				b.visit_mut(visitor);
+			},
+			Expression::Operation3(_, a, b, c) => {
+				a.visit_mut(visitor);
+				b.visit_mut(visitor);
+				c.visit_mut(visitor);
+			},
+			Expression::OperationN(_, lines) | Expression::Scope(lines) | Expression::Invalid(_, lines) | Expression::Array(lines) | Expression::QueryFunction(_, lines) => {
+				for line in lines.iter_mut() {
+					line.visit_mut(visitor);
+				}
+			}
+		}
+	}
+
+	// Collects assignments that are overwritten before the variable is read. Branches, loops and isolated scopes may not run
+	// or run several times, so assignments inside of them are only checked for reads
+	fn find_dead_stores(&self, pending: &mut Vec<String>, dead: &mut Vec<String>) {
+		let reads = |node: &Expression, pending: &mut Vec<String>| node.visit(&mut |node| {
+			if let Expression::Variable(name, _) = node {
+				pending.retain(|n| n != name);
+			}
+		});
+		match self {
+			Expression::Number(_) | Expression::String(_) | Expression::This => {},
+			Expression::Variable(name, _) => pending.retain(|n| n != name),
+			Expression::Allocation(name, _, value) => {
+				value.find_dead_stores(pending, dead);
+				if !pending.contains(name) {
+					pending.push(name.clone());
+				} else if !dead.contains(name) {
+					dead.push(name.clone());
+				}
+			},
+			Expression::Operation1(OperationType::IsolatedScope, a) => reads(a, pending),
+			Expression::Operation2(OperationType::And | OperationType::Or | OperationType::NullCoalescing | OperationType::Ternary | OperationType::MapLoop, a, b) |
+			Expression::Loop(a, _, b) => {
+				a.find_dead_stores(pending, dead);
+				reads(b, pending);
+			},
+			Expression::Operation3(OperationType::Ternary, a, b, c) => {
+				a.find_dead_stores(pending, dead);
+				reads(b, pending);
+				reads(c, pending);
+			},
+			Expression::Operation1(_, a) | Expression::ReturnStatement(a) => a.find_dead_stores(pending, dead),
+			Expression::Operation2(_, a, b) | Expression::Index(a, b) => {
+				a.find_dead_stores(pending, dead);
+				b.find_dead_stores(pending, dead);
+			},
+			Expression::Operation3(_, a, b, c) => {
+				a.find_dead_stores(pending, dead);
+				b.find_dead_stores(pending, dead);
+				c.find_dead_stores(pending, dead);
+			},
+			Expression::OperationN(_, lines) | Expression::Scope(lines) | Expression::Invalid(_, lines) | Expression::Array(lines) | Expression::QueryFunction(_, lines) => {
+				for line in lines.iter() {
+					line.find_dead_stores(pending, dead);
+					// Nothing after a return statement runs
+					if let Expression::ReturnStatement(_) = line {break;}
+				}
+			}
+		}
+	}
+
+	// Writes an indented tree of this node and its descendants, one node per line
+	fn dump(&self, depth: usize, output: &mut String) {
+		output.push_str(&"  ".repeat(depth));
+		let children: Vec<&Expression> = match self {
+			Expression::Number(num) => {
+				output.push_str(&format!("Number({})\n", num));
+				vec![]
+			},
+			Expression::This => {
+				output.push_str("This\n");
+				vec![]
+			},
+			Expression::Variable(name, _) => {
+				output.push_str(&format!("Variable({})\n", name));
+				vec![]
+			},
+			Expression::Operation1(o_type, a) => {
+				output.push_str(&format!("{:?}\n", o_type));
+				vec![a]
+			},
+			Expression::Operation2(o_type, a, b) => {
+				output.push_str(&format!("{:?}\n", o_type));
+				vec![a, b]
+			},
+			Expression::Operation3(o_type, a, b, c) => {
+				output.push_str(&format!("{:?}\n", o_type));
+				vec![a, b, c]
+			},
+			Expression::OperationN(o_type, args) => {
+				output.push_str(&format!("{:?}\n", o_type));
+				args.iter().collect()
+			},
+			Expression::Allocation(name, _, a) => {
+				output.push_str(&format!("Allocation({})\n", name));
+				vec![a]
+			},
+			Expression::ReturnStatement(a) => {
+				output.push_str("Return\n");
+				vec![a]
+			},
+			Expression::Loop(count, index_variable, scope) => {
+				match index_variable {
+					Some((name, _)) => output.push_str(&format!("Loop({})\n", name)),
+					None => output.push_str("Loop\n")
+				}
+				vec![count, scope]
+			},
+			Expression::Scope(lines) => {
+				output.push_str("Scope\n");
+				lines.iter().collect()
+			},
+			Expression::Invalid(error, parts) => {
+				output.push_str(&format!("Invalid({})\n", error));
+				parts.iter().collect()
+			},
+			Expression::Array(items) => {
+				output.push_str("Array\n");
+				items.iter().collect()
+			},
+			Expression::String(string) => {
+				output.push_str(&format!("String('{}')\n", string));
+				vec![]
+			},
+			Expression::QueryFunction(name, args) => {
+				output.push_str(&format!("QueryFunction({})\n", name));
+				args.iter().collect()
+			},
+			Expression::Index(array, index) => {
+				output.push_str("Index\n");
+				vec![array, index]
+			}
+		};
+		for child in children {
+			child.dump(depth + 1, output);
+		}
+	}
+
+	// Returns the first parse error in the tree
+	fn find_error(&self) -> Option<MolangError> {
+		let mut error = None;
+		self.visit(&mut |node| {
+			if let (None, Expression::Invalid(node_error, _)) = (&error, node) {
+				error = Some(node_error.clone());
+			}
+		});
+		error
+	}
+
+	fn contains(&self, predicate: fn(&Expression) -> bool) -> bool {
+		let mut found = false;
+		self.visit(&mut |node| {
+			if predicate(node) {found = true;}
+		});
+		found
+	}
+
+	fn is_random(&self) -> bool {
+		matches!(self,
+			Expression::Operation1(OperationType::Random | OperationType::RandomInt, _) |
+			Expression::Operation2(OperationType::Random, _, _) |
+			Expression::Operation2(OperationType::RandomInt, _, _) |
+			Expression::Operation3(OperationType::Dieroll, _, _, _) |
+			Expression::Operation3(OperationType::DierollInt, _, _, _)
+		)
+	}
+
+	// Rough relative cost of evaluating this node, not counting its children
+	fn cost(&self) -> usize {
+		if self.is_random() {
+			return 4;
+		}
+		match self {
+			Expression::Operation1(op, _) | Expression::Operation2(op, _, _) | Expression::Operation3(op, _, _, _) => match op {
+				OperationType::Sin | OperationType::Cos | OperationType::Asin | OperationType::Acos | OperationType::Atan |
+				OperationType::Atan2 | OperationType::Pow | OperationType::Exp | OperationType::Ln | OperationType::Sqrt |
+				OperationType::Lerprotate | OperationType::HermiteBlend => 4,
+				OperationType::MapLoop => 8,
+				_ => 1
+			},
+			Expression::QueryFunction(_, _) => 2,
+			// The body of a loop is only counted once
+			Expression::Loop(_, _, _) => 8,
+			_ => 1
+		}
+	}
+
+	fn is_constant(&self) -> bool {
+		!self.contains(|node| {
+			node.is_random() || matches!(node, Expression::Variable(_, _) | Expression::QueryFunction(_, _) | Expression::Allocation(_, _, _) |
+				Expression::Loop(_, _, _) | Expression::Operation2(OperationType::MapLoop, _, _))
+		})
+	}
+
+	fn is_numeric(&self) -> bool {
+		matches!(self.kind(), ValueKind::Number | ValueKind::Bool)
+	}
+
+	// Turns the tree into nested closures, so that evaluating it doesn't need to match on the node types.
+	// Constant parts are evaluated once. Nodes that need the full evaluation state, like scopes, fall back to walking the tree
+	fn into_closure(self) -> Box<MolangFn> {
+		if self.is_constant() {
+			let value = self.eval(&mut EvalState::new(&mut HashMap::new()));
+			return Box::new(move |_| value);
+		}
+		match self {
+			Expression::Variable(name, _) => Box::new(move |variables| variables.get(&name).map_or(0.0, MolangValue::as_number)),
+			Expression::Operation1(op @ (OperationType::Invert | OperationType::Abs | OperationType::Sin | OperationType::Cos |
+				OperationType::Sqrt | OperationType::Floor | OperationType::Ceil | OperationType::Round), a) => {
+				let a = (*a).into_closure();
+				match op {
+					OperationType::Invert => Box::new(move |variables| -a(variables)),
+					OperationType::Abs => Box::new(move |variables| a(variables).abs()),
+					OperationType::Sin => Box::new(move |variables| (a(variables) * ANGLE_FACTOR).sin()),
+					OperationType::Cos => Box::new(move |variables| (a(variables) * ANGLE_FACTOR).cos()),
+					OperationType::Sqrt => Box::new(move |variables| a(variables).sqrt()),
+					OperationType::Floor => Box::new(move |variables| a(variables).floor()),
+					OperationType::Ceil => Box::new(move |variables| a(variables).ceil()),
+					_ => Box::new(move |variables| a(variables).round())
+				}
+			},
+			Expression::Operation2(op @ (OperationType::Add | OperationType::Subtract | OperationType::Multiply |
+				OperationType::Divide | OperationType::Modulo | OperationType::Pow), a, b) => {
+				let (a, b) = ((*a).into_closure(), (*b).into_closure());
+				match op {
+					OperationType::Add => Box::new(move |variables| a(variables) + b(variables)),
+					OperationType::Subtract => Box::new(move |variables| a(variables) - b(variables)),
+					OperationType::Multiply => Box::new(move |variables| a(variables) * b(variables)),
+					OperationType::Divide => Box::new(move |variables| a(variables) / b(variables)),
+					OperationType::Modulo => Box::new(move |variables| a(variables) % b(variables)),
+					_ => Box::new(move |variables| a(variables).powf(b(variables)))
+				}
+			},
+			// Strings compare differently, so only numbers are compared directly
+			Expression::Operation2(op @ (OperationType::Smaller | OperationType::SmallerEqual | OperationType::Larger | OperationType::LargerEqual), a, b)
+				if a.is_numeric() && b.is_numeric() => {
+				let (a, b) = ((*a).into_closure(), (*b).into_closure());
+				let bool_value = |value: bool| if value {1.0} else {0.0};
+				match op {
+					OperationType::Smaller => Box::new(move |variables| bool_value(a(variables) < b(variables))),
+					OperationType::SmallerEqual => Box::new(move |variables| bool_value(a(variables) <= b(variables))),
+					OperationType::Larger => Box::new(move |variables| bool_value(a(variables) > b(variables))),
+					_ => Box::new(move |variables| bool_value(a(variables) >= b(variables)))
+				}
+			},
+			Expression::Operation3(OperationType::Ternary, a, b, c) if a.is_numeric() => {
+				let (a, b, c) = ((*a).into_closure(), (*b).into_closure(), (*c).into_closure());
+				Box::new(move |variables| if a(variables) != 0.0 {b(variables)} else {c(variables)})
+			},
+			Expression::Operation3(op @ (OperationType::Clamp | OperationType::Lerp), a, b, c) => {
+				let (a, b, c) = ((*a).into_closure(), (*b).into_closure(), (*c).into_closure());
+				match op {
+					OperationType::Clamp => Box::new(move |variables| math::clamp(a(variables), b(variables), c(variables))),
+					_ => Box::new(move |variables| math::lerp(a(variables), b(variables), c(variables)))
+				}
+			},
+			expression => Box::new(move |variables| expression.eval(&mut EvalState::new(variables)))
+		}
+	}
+
+	// Best-effort static guess of the kind of value this expression evaluates to
+	fn kind(&self) -> ValueKind {
+		match self {
+			Expression::Number(_) | Expression::Allocation(_, _, _) | Expression::This => ValueKind::Number,
+			Expression::Operation1(OperationType::Negate, _) => ValueKind::Bool,
+			Expression::Operation2(op_type, a, b) => match op_type {
+				OperationType::And | OperationType::Or |
+				OperationType::Smaller | OperationType::SmallerEqual |
+				OperationType::Larger | OperationType::LargerEqual |
+				OperationType::Equal | OperationType::Unequal => ValueKind::Bool,
+				OperationType::NullCoalescing => a.kind().join(b.kind()),
+				// "a ? b" evaluates to 0 if the condition is false
+				OperationType::Ternary => b.kind().join(ValueKind::Number),
+				OperationType::MapLoop => ValueKind::Array,
+				_ => ValueKind::Number
+			},
+			Expression::Operation3(op_type, _, b, c) => match op_type {
+				OperationType::Ternary => b.kind().join(c.kind()),
+				OperationType::InRange => ValueKind::Bool,
+				_ => ValueKind::Number
+			},
+			Expression::OperationN(OperationType::All, _) |
+			Expression::OperationN(OperationType::Any, _) |
+			Expression::OperationN(OperationType::ApproxEq, _) => ValueKind::Bool,
+			Expression::Operation1(OperationType::IsDefined, _) => ValueKind::Bool,
+			Expression::Operation1(_, _) | Expression::OperationN(_, _) => ValueKind::Number,
+			Expression::ReturnStatement(a) => a.kind(),
+			Expression::Array(_) => ValueKind::Array,
+			Expression::String(_) => ValueKind::String,
+			Expression::QueryFunction(_, _) => ValueKind::Unknown,
+			Expression::Index(_, _) => ValueKind::Number,
+			Expression::Variable(_, _) | Expression::Loop(_, _, _) | Expression::Scope(_) | Expression::Invalid(_, _) => ValueKind::Unknown,
+		}
+	}
+}
+
+/// The kind of value an expression evaluates to, as far as it can be told without evaluating it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+	Number,
+	/// 1 or 0
+	Bool,
+	Array,
+	String,
+	Unknown,
+}
+impl ValueKind {
+	fn join(self, other: ValueKind) -> ValueKind {
+		if self == other {self} else {ValueKind::Unknown}
+	}
+}
+
+// Removes whitespace and lowercases the input, except inside string literals
+fn normalize_input(input: &str) -> String {
+	let mut output = String::with_capacity(input.len());
+	let mut in_string = false;
+	for (i, ch) in input.char_indices() {
+		if ch == '\'' && is_quote(input, i) {
+			in_string = !in_string;
+			output.push(ch);
+		} else if in_string {
+			output.push(ch);
+		} else if !ch.is_whitespace() {
+			output.extend(ch.to_lowercase());
+		}
+	}
+	output
+}
+
+// Expands custom prefixes like `m.` to their full namespace, outside of strings and member names
+fn expand_aliases(input: &str, aliases: &HashMap<char, String>) -> String {
+	let mut output = String::with_capacity(input.len());
+	let mut in_string = false;
+	let mut previous: Option<char> = None;
+	let mut chars = input.char_indices().peekable();
+	while let Some((i, ch)) = chars.next() {
+		if ch == '\'' && is_quote(input, i) {
+			in_string = !in_string;
+		}
+		let starts_name = !previous.is_some_and(|p| p.is_alphanumeric() || p == '_' || p == '.');
+		match aliases.get(&ch.to_ascii_lowercase()) {
+			Some(full) if !in_string && starts_name && chars.peek().map(|(_, next)| *next) == Some('.') => output.push_str(full),
+			_ => output.push(ch)
+		}
+		previous = Some(ch);
+	}
+	output
+}
+
+fn create_expression_tree(string: &str, max_depth: usize) -> Expression {
 	
-	let input = string.replace(' ', "").to_lowercase();
+	let input = normalize_input(string);
+
+	parse_string_slice(&input, max_depth)
+
+}
+
+// Expands the aliases in the input and parses it
+fn compile_script(input: &str, aliases: &HashMap<char, String>, max_depth: usize) -> CompiledMolang {
+	if aliases.is_empty() {
+		return CompiledMolang::new(create_expression_tree(input, max_depth));
+	}
+	CompiledMolang::new(create_expression_tree(&expand_aliases(input, aliases), max_depth))
+}
+
+/// A parsed Molang expression that can be evaluated repeatedly
+#[derive(Clone)]
+pub struct CompiledMolang {
+	expression: Expression,
+	error: Option<MolangError>,
+	// Names of all variables that are read or written, their positions are the ids used by `eval_slice`
+	variable_names: Vec<String>
+}
+impl CompiledMolang {
+	fn new(mut expression: Expression) -> Self {
+		// An expression that is nested too deeply is discarded as a whole
+		if expression.contains(|node| matches!(node, Expression::Invalid(MolangError::DepthExceeded, _))) {
+			expression = Expression::Invalid(MolangError::DepthExceeded, vec![]);
+		}
+		let error = expression.find_error();
+		let mut variable_names: Vec<String> = Vec::new();
+		expression.visit_mut(&mut |node| {
+			if let Expression::Variable(name, slot) | Expression::Allocation(name, slot, _) | Expression::Loop(_, Some((name, slot)), _) = node {
+				*slot = match variable_names.iter().position(|n| n == name) {
+					Some(id) => id,
+					None => {
+						variable_names.push(name.clone());
+						variable_names.len() - 1
+					}
+				};
+			}
+		});
+		Self {expression, error, variable_names}
+	}
+	pub fn eval(&self, variables: &mut HashMap<String, MolangValue>) -> f32 {
+		self.expression.eval(&mut EvalState::new(variables))
+	}
+	/// The first error found while parsing. Invalid parts of an expression evaluate to 0
+	pub fn error(&self) -> Option<&MolangError> {
+		self.error.as_ref()
+	}
+	fn run(&self, state: &mut EvalState, strict: bool) -> Result<f32, MolangError> {
+		if let (Some(error), true) = (&self.error, strict) {
+			return Err(error.clone());
+		}
+		let result = self.expression.eval(state);
+		if state.budget_exceeded {
+			return Err(MolangError::BudgetExceeded);
+		}
+		if let (Some(name), true) = (state.undefined_variable.take(), strict) {
+			return Err(MolangError::UndefinedVariable(name));
+		}
+		Ok(math::normalize_zero(result))
+	}
+	/// True if the expression has no variables, queries, randomness or loops, so it can be evaluated once ahead of time
+	pub fn is_constant(&self) -> bool {
+		self.expression.is_constant()
+	}
+	/// True if the expression is deterministic: it may read variables, but doesn't write them or use randomness
+	pub fn is_pure(&self) -> bool {
+		// Loops without an index variable, like map_loop, don't write anything
+		!self.expression.contains(|node| {
+			node.is_random() || matches!(node, Expression::Allocation(_, _, _) | Expression::Loop(_, Some(_), _))
+		})
+	}
+	/// Weighted number of nodes in the expression, where functions like trigonometry, powers and randomness count more than
+	/// arithmetic. Can be used to reject scripts that are too expensive
+	pub fn complexity(&self) -> usize {
+		let mut complexity = 0;
+		self.expression.visit(&mut |node| complexity += node.cost());
+		complexity
+	}
+	/// Number of distinct variables in the expression, which is the size of the slice that `eval_slice` needs
+	pub fn var_count(&self) -> usize {
+		self.variable_names.len()
+	}
+	/// The index of a variable in the slice passed to `eval_slice`
+	pub fn var_id(&self, name: &str) -> Option<usize> {
+		let name = normalize_name(name);
+		self.variable_names.iter().position(|n| *n == name)
+	}
+	/// Evaluates against variables stored in a slice, indexed by `var_id`, instead of a map.
+	/// Every variable counts as set, and arrays are stored as 0
+	pub fn eval_slice(&self, variables: &mut [f32]) -> f32 {
+		self.expression.eval(&mut EvalState::with_storage(VariableStorage::Slice(&self.variable_names, variables)))
+	}
+	/// Evaluates without being able to change the variables. Returns an error if the expression assigns a variable
+	pub fn eval_readonly(&self, variables: &HashMap<String, MolangValue>) -> Result<f32, MolangError> {
+		let mut assigned = None;
+		self.expression.visit(&mut |node| {
+			if let (None, Expression::Allocation(name, _, _) | Expression::Loop(_, Some((name, _)), _)) = (&assigned, node) {
+				assigned = Some(name.clone());
+			}
+		});
+		if let Some(name) = assigned {
+			return Err(MolangError::InvalidAssignmentTarget(name));
+		}
+		Ok(self.expression.eval(&mut EvalState::with_storage(VariableStorage::ReadOnly(variables))))
+	}
+	/// Guesses the kind of value the expression evaluates to, without evaluating it
+	pub fn result_kind(&self) -> ValueKind {
+		self.expression.kind()
+	}
+	/// Evaluates a constant expression without a variable map. Returns None if the expression isn't constant
+	pub fn eval_const(&self) -> Option<f32> {
+		if !self.is_constant() {
+			return None;
+		}
+		// An empty map doesn't allocate, and constant expressions never write to it
+		Some(self.expression.eval(&mut EvalState::new(&mut HashMap::new())))
+	}
+	/// Turns the expression into a closure that gives the same results as `eval`, but is faster to call repeatedly
+	pub fn into_fn(self) -> Box<MolangFn> {
+		self.expression.into_closure()
+	}
+}
+
+/// Builds expressions directly, without writing and parsing Molang source
+pub mod ast {
+	use super::{CompiledMolang, Expression, OperationType, normalize_name};
+
+	fn operation_2(op_type: OperationType, a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		CompiledMolang::new(Expression::Operation2(op_type, Box::new(a.expression), Box::new(b.expression)))
+	}
+
+	pub fn number(value: f32) -> CompiledMolang {
+		CompiledMolang::new(Expression::Number(value))
+	}
+	/// A variable, the name can be abbreviated like `v.x`
+	pub fn variable(name: &str) -> CompiledMolang {
+		CompiledMolang::new(Expression::Variable(normalize_name(name), 0))
+	}
+	pub fn add(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Add, a, b)
+	}
+	pub fn sub(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Subtract, a, b)
+	}
+	pub fn mul(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Multiply, a, b)
+	}
+	pub fn div(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Divide, a, b)
+	}
+	pub fn pow(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Pow, a, b)
+	}
+	pub fn min(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Min, a, b)
+	}
+	pub fn max(a: CompiledMolang, b: CompiledMolang) -> CompiledMolang {
+		operation_2(OperationType::Max, a, b)
+	}
+	pub fn neg(a: CompiledMolang) -> CompiledMolang {
+		CompiledMolang::new(Expression::Operation1(OperationType::Invert, Box::new(a.expression)))
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MolangError {
+	/// The input was empty or only contained whitespace
+	Empty,
+	/// A `math.` function or constant that doesn't exist
+	UnknownFunction(String),
+	/// An assignment to something that can't be assigned to, like an array index
+	InvalidAssignmentTarget(String),
+	/// A variable was read without being set, while `UndefinedBehavior::Error` is configured
+	UndefinedVariable(String),
+	/// A name that is empty, has an empty member like `v..x`, contains symbols, or is only a namespace like `v`
+	MalformedIdentifier(String),
+	/// The expression is nested deeper than the parser's `max_depth`
+	DepthExceeded,
+	/// Evaluation was stopped because it exceeded the node budget
+	BudgetExceeded,
+}
+impl std::fmt::Display for MolangError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			MolangError::Empty => write!(f, "Expression is empty"),
+			MolangError::UnknownFunction(name) => write!(f, "Unknown function '{}'", name),
+			MolangError::InvalidAssignmentTarget(target) => write!(f, "Cannot assign to '{}'", target),
+			MolangError::MalformedIdentifier(name) => write!(f, "Malformed name '{}'", name),
+			MolangError::UndefinedVariable(name) => write!(f, "Variable '{}' is not set", name),
+			MolangError::BudgetExceeded => write!(f, "Evaluation exceeded its budget"),
+			MolangError::DepthExceeded => write!(f, "Expression is nested too deeply"),
+		}
+	}
+}
+impl std::error::Error for MolangError {}
+
+/// Variable storage that can be evaluated against while sharing a parser's cache
+#[derive(Default)]
+pub struct MolangContext {
+	variables: HashMap<String, MolangValue>
+}
+
+/// Named scripts that are compiled together, like the expressions of an animation controller, see `MolangParser::compile_set`
+#[derive(Clone, Default)]
+pub struct MolangScriptSet {
+	scripts: HashMap<String, CompiledMolang>
+}
+impl MolangScriptSet {
+	/// Evaluates the script with the given name, or returns None if there is no such script
+	pub fn eval(&self, name: &str, variables: &mut HashMap<String, MolangValue>) -> Option<f32> {
+		self.scripts.get(name).map(|script| script.eval(variables))
+	}
+	pub fn get(&self, name: &str) -> Option<&CompiledMolang> {
+		self.scripts.get(name)
+	}
+	pub fn names(&self) -> impl Iterator<Item = &str> {
+		self.scripts.keys().map(String::as_str)
+	}
+}
 
-	let expression = parse_string_slice(&input);
-	//println!("Expression: {:?}", expression);
-	expression
+/// What reading a variable that isn't set evaluates to
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UndefinedBehavior {
+	#[default]
+	Zero,
+	Nan,
+	/// Evaluates to 0, but makes `try_parse` return `MolangError::UndefinedVariable`
+	Error
+}
 
+/// A saved copy of a parser's variables and globals, see `MolangParser::snapshot_variables`
+#[derive(Debug, Clone, Default)]
+pub struct VariableSnapshot {
+	variables: HashMap<String, MolangValue>,
+	globals: HashMap<String, MolangValue>
 }
 
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Parses and evaluates Molang expressions, caching the parsed trees by input string.
+/// All math, including intermediate results, is done in `f32` to match the precision of shaders and the game.
 pub struct MolangParser {
-	cache: HashMap<String, Expression>,
-	variables: HashMap<String, f32>,
-	pub enable_cache: bool
+	cache: HashMap<String, CompiledMolang>,
+	// Scripts that queries evaluate through their context. They can't go into `cache`, which is borrowed while the outer script runs
+	nested_scripts: RefCell<HashMap<String, Rc<CompiledMolang>>>,
+	variables: HashMap<String, MolangValue>,
+	globals: HashMap<String, MolangValue>,
+	// Shared with parsers created by `clone_config`
+	variable_resolver: Option<Rc<VariableResolver>>,
+	queries: HashMap<String, Rc<QueryCallback>>,
+	aliases: HashMap<char, String>,
+	#[cfg(feature = "tracing")]
+	trace_hook: Option<Rc<TraceHook>>,
+	pub enable_cache: bool,
+	/// If set, `math.min` and `math.max` return NaN if either argument is NaN. By default, NaN arguments are ignored
+	pub propagate_nan: bool,
+	/// How far apart numbers may be for `==` and `!=` to treat them as equal. By default this is 0, so comparisons are exact
+	/// and `0.3 + 0.6 == 0.9` is false. `math.equals_exact` always compares exactly
+	pub equality_epsilon: f32,
+	/// What variables that aren't set evaluate to. `??` and `math.is_defined` aren't affected
+	pub undefined_behavior: UndefinedBehavior,
+	/// Whether variables that a script sets are kept for later evaluations. If not, they are cleared before each evaluation,
+	/// so scripts can't affect each other. Globals are always kept
+	pub persistent_variables: bool,
+	/// How deeply expressions may be nested. Deeper parts are invalid and evaluate to 0, which keeps
+	/// untrusted input from overflowing the stack while parsing or evaluating
+	pub max_depth: usize
 }
 impl MolangParser {
 	pub fn new() -> Self {
 		Self {
 			cache: HashMap::new(),
+			nested_scripts: RefCell::new(HashMap::new()),
 			variables: HashMap::new(),
-			enable_cache: true
+			globals: HashMap::new(),
+			variable_resolver: None,
+			queries: HashMap::new(),
+			aliases: HashMap::new(),
+			#[cfg(feature = "tracing")]
+			trace_hook: None,
+			enable_cache: true,
+			propagate_nan: false,
+			equality_epsilon: 0.0,
+			undefined_behavior: UndefinedBehavior::Zero,
+			persistent_variables: true,
+			max_depth: DEFAULT_MAX_DEPTH
 		}
 	}
-	pub fn parse(&mut self, input: String) -> f32 {
+	pub fn parse(&mut self, input: &str) -> f32 {
+		self.parse_cow(Cow::Borrowed(input))
+	}
+	/// Like `parse`, but on a cache miss the input is moved into the cache instead of being copied
+	pub fn parse_owned(&mut self, input: String) -> f32 {
+		self.parse_cow(Cow::Owned(input))
+	}
+	fn parse_cow(&mut self, input: Cow<str>) -> f32 {
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let result = self.evaluate(input, &mut variables, false, None);
+		self.variables = variables;
+		result.unwrap_or(0.0)
+	}
+	/// The math and query functions that are built into the parser
+	pub fn builtins() -> &'static [Builtin] {
+		&BUILTINS
+	}
+	/// Like `parse`, but reports invalid input as an error instead of evaluating it to 0
+	pub fn try_parse(&mut self, input: &str) -> Result<f32, MolangError> {
+		if input.trim().is_empty() {
+			return Err(MolangError::Empty);
+		}
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let result = self.evaluate(Cow::Borrowed(input), &mut variables, true, None);
+		self.variables = variables;
+		result
+	}
+	/// Creates an empty variable context for use with `parse_with_context`
+	pub fn new_context(&self) -> MolangContext {
+		MolangContext::default()
+	}
+	/// Evaluates the input against the variables of the context instead of the parser's own
+	pub fn parse_with_context(&mut self, input: &str, context: &mut MolangContext) -> f32 {
+		self.evaluate(Cow::Borrowed(input), &mut context.variables, false, None).unwrap_or(0.0)
+	}
+	/// Like `try_parse`, but stops with an error once more than `max_nodes` nodes of the expression tree have been evaluated
+	pub fn parse_budgeted(&mut self, input: &str, max_nodes: u64) -> Result<f32, MolangError> {
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let result = self.evaluate(Cow::Borrowed(input), &mut variables, true, Some(max_nodes));
+		self.variables = variables;
+		result
+	}
+	// In strict mode, errors are returned instead of evaluating invalid parts to 0.
+	// An owned input becomes the cache key without being copied
+	fn evaluate(&mut self, input: Cow<str>, variables: &mut HashMap<String, MolangValue>, strict: bool, budget: Option<u64>) -> Result<f32, MolangError> {
 
 		if input.len() == 0 {
-			return 0.0;
+			return Ok(0.0);
 		}
-		if input.len() < 9 && is_string_number(&input) {
-			return input.parse().unwrap();
+		if let Some(value) = parse_number(&input) {
+			return Ok(math::normalize_zero(value));
 		}
 
+		let mut state = self.eval_state(variables);
+		state.budget = budget;
+
 		if self.enable_cache == false {
-			let script = create_expression_tree(&input);
+			let script = self.compile(&input);
 			
-			return script.eval(&mut self.variables);
+			return script.run(&mut state, strict);
 		}
 		let cache_result = {
-			self.cache.get(&input)
+			self.cache.get(input.as_ref())
 		};
 		match cache_result {
 			Some(script) => {
-				script.eval(&mut self.variables)
+				script.run(&mut state, strict)
 			},
 			None => {
-				let script = create_expression_tree(&input);
+				let script = self.compile(&input);
 				
-				let result = script.eval(&mut self.variables);
+				let result = script.run(&mut state, strict);
 
-				self.cache.insert(input.clone(), script);
+				self.cache.insert(input.into_owned(), script);
 				result
 			}
 		}
 	}
+	/// Evaluates the input and stores the result in a variable, for example to read the previous frame's result in the next one
+	pub fn parse_and_store(&mut self, input: &str, store_as: &str) -> f32 {
+		let result = self.parse(input);
+		self.variables.insert(normalize_name(store_as), MolangValue::Number(result));
+		result
+	}
+	/// Evaluates a script that writes its results to variables, and reads those outputs, like `&["v.offset", "t.scale"]`.
+	/// Outputs that the script didn't set are read from the globals, or are 0
+	pub fn parse_outputs(&mut self, input: &str, outputs: &[&str]) -> Vec<f32> {
+		self.parse(input);
+		outputs.iter().map(|name| {
+			let name = normalize_name(name);
+			self.variables.get(&name).or_else(|| self.globals.get(&name)).map_or(0.0, MolangValue::as_number)
+		}).collect()
+	}
+	/// Evaluates the input and rounds the result half to even, the same way loop counts are rounded
+	pub fn parse_int(&mut self, input: &str) -> i64 {
+		math::to_int(self.parse(input))
+	}
+	/// Evaluates the input without reading or writing the cache, for inputs that are known not to repeat
+	pub fn parse_uncached(&mut self, input: &str) -> f32 {
+		let script = self.compile(input);
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let result = script.expression.eval(&mut self.eval_state(&mut variables));
+		self.variables = variables;
+		math::normalize_zero(result)
+	}
+	// Builds the state for evaluating with the parser's options, globals, queries and aliases
+	fn eval_state<'a>(&'a self, variables: &'a mut HashMap<String, MolangValue>) -> EvalState<'a> {
+		let mut state = EvalState::new(variables);
+		state.resolver = self.variable_resolver.as_deref();
+		state.globals = Some(&self.globals);
+		state.queries = Some(&self.queries);
+		state.aliases = Some(&self.aliases);
+		state.max_depth = self.max_depth;
+		if self.enable_cache {
+			state.nested_scripts = Some(&self.nested_scripts);
+		}
+		#[cfg(feature = "tracing")]
+		{state.trace = self.trace_hook.as_deref();}
+		state.propagate_nan = self.propagate_nan;
+		state.equality_epsilon = self.equality_epsilon;
+		state.undefined_behavior = self.undefined_behavior;
+		state
+	}
+	/// Number of compiled expressions in the cache
+	pub fn cache_len(&self) -> usize {
+		self.cache.len()
+	}
+	/// Whether the input has been compiled into the cache
+	pub fn is_cached(&self, input: &str) -> bool {
+		self.cache.contains_key(input)
+	}
+	/// Compiles the inputs into the cache without evaluating them
+	pub fn precompile(&mut self, inputs: &[&str]) {
+		for input in inputs {
+			if !self.cache.contains_key(*input) {
+				let script = self.compile(input);
+				self.cache.insert(input.to_string(), script);
+			}
+		}
+	}
+	/// Evaluates the input and formats it with at most `precision` decimals, without trailing zeros
+	pub fn parse_formatted(&mut self, input: &str, precision: usize) -> String {
+		let formatted = format!("{:.*}", precision, self.parse(input));
+		let trimmed = if formatted.contains('.') {
+			formatted.trim_end_matches('0').trim_end_matches('.')
+		} else {
+			&formatted
+		};
+		// Small negative numbers keep their sign when they round to zero
+		if trimmed == "-0" {"0".to_string()} else {trimmed.to_string()}
+	}
+	/// Evaluates a script that returns an array like `[a, b, c]`, or assigns `temp.x`, `temp.y` and `temp.z`, into three components
+	pub fn parse_vec3(&mut self, input: &str) -> [f32; 3] {
+		self.clear_if_stateless();
+		if self.enable_cache {
+			self.precompile(&[input]);
+		}
+		let uncached;
+		let script = match self.cache.get(input) {
+			Some(script) if self.enable_cache => script,
+			_ => {
+				uncached = self.compile(input);
+				&uncached
+			}
+		};
+		let mut variables = std::mem::take(&mut self.variables);
+		let mut state = self.eval_state(&mut variables);
+		let components = match script.expression.eval_value(&mut state) {
+			MolangValue::Array(items) => {
+				let component = |i: usize| items.get(i).copied().unwrap_or(0.0);
+				[component(0), component(1), component(2)]
+			},
+			_ => {
+				// The state stores variables in a map, which doesn't use slots
+				let component = |name: &str| state.get_variable(name, 0).unwrap_or(0.0);
+				[component("temp.x"), component("temp.y"), component("temp.z")]
+			}
+		};
+		self.variables = variables;
+		components.map(math::normalize_zero)
+	}
+	/// Evaluates the input and returns the result along with an indented dump of the expression tree
+	pub fn parse_debug(&mut self, input: &str) -> (f32, String) {
+		let script = self.compile(input);
+		let mut tree = String::new();
+		script.expression.dump(0, &mut tree);
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let result = script.expression.eval(&mut self.eval_state(&mut variables));
+		self.variables = variables;
+		(math::normalize_zero(result), tree)
+	}
+	/// Evaluates the input one top-level statement at a time, calling `on_step` with the statement index, its value and the variables after it
+	pub fn eval_stepwise(&mut self, input: &str, mut on_step: impl FnMut(usize, f32, &HashMap<String, MolangValue>)) -> f32 {
+		let script = self.compile(input);
+		let lines = match &script.expression {
+			Expression::Scope(lines) => lines.iter().collect(),
+			expression => vec![expression]
+		};
+		self.clear_if_stateless();
+		let mut variables = std::mem::take(&mut self.variables);
+		let mut state = self.eval_state(&mut variables);
+		let mut return_value: f32 = 0.0;
+		for (index, line) in lines.into_iter().enumerate() {
+			return_value = math::normalize_zero(line.eval(&mut state));
+			state.this = return_value;
+			if let VariableStorage::Map(variables) = &state.variables {
+				on_step(index, return_value, variables);
+			}
+			if state.returning {break;}
+		}
+		self.variables = variables;
+		return_value
+	}
+	// Without persistent variables, each evaluation starts without the variables of earlier ones
+	fn clear_if_stateless(&mut self) {
+		if !self.persistent_variables {
+			self.variables.clear();
+		}
+	}
+	/// Evaluates the input and returns the value of each top-level statement, up to a return statement
+	pub fn eval_all(&mut self, input: &str) -> Vec<f32> {
+		let mut values = Vec::new();
+		self.eval_stepwise(input, |_, value, _| values.push(value));
+		values
+	}
+	/// Sets a global variable that is shared by all evaluations and contexts, and isn't removed by `clear_variables`.
+	/// Variables that are set during evaluation take precedence over globals with the same name
+	pub fn set_global(&mut self, name: &str, value: impl Into<MolangValue>) {
+		self.globals.insert(normalize_name(name), value.into());
+	}
+	/// Creates a parser with the same options, resolver and queries, but an empty cache and no variables or globals
+	pub fn clone_config(&self) -> MolangParser {
+		MolangParser {
+			variable_resolver: self.variable_resolver.clone(),
+			queries: self.queries.clone(),
+			aliases: self.aliases.clone(),
+			#[cfg(feature = "tracing")]
+			trace_hook: self.trace_hook.clone(),
+			enable_cache: self.enable_cache,
+			propagate_nan: self.propagate_nan,
+			equality_epsilon: self.equality_epsilon,
+			undefined_behavior: self.undefined_behavior,
+			persistent_variables: self.persistent_variables,
+			max_depth: self.max_depth,
+			..MolangParser::new()
+		}
+	}
+	/// Sets a variable to an array, which scripts can read by index like `v.points[2]`.
+	/// Without `persistent_variables` the array is stored as a global, since variables are cleared before every evaluation
+	pub fn set_array(&mut self, name: &str, values: Vec<f32>) {
+		let target = if self.persistent_variables {&mut self.variables} else {&mut self.globals};
+		target.insert(normalize_name(name), MolangValue::Array(values));
+	}
+	/// Removes all variables that were set during evaluation
+	pub fn clear_variables(&mut self) {
+		self.variables.clear();
+	}
+	/// Iterates over the variables that were set during evaluation, by their full names like `variable.health`
+	pub fn variables_iter(&self) -> impl Iterator<Item = (&str, &MolangValue)> {
+		self.variables.iter().map(|(name, value)| (name.as_str(), value))
+	}
+	/// Lists the variables that were set during evaluation, one `name = value` line per variable, sorted by name
+	pub fn dump_variables(&self) -> String {
+		let mut lines: Vec<String> = self.variables_iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+		lines.sort();
+		lines.join("\n")
+	}
+	/// Saves the variables and globals, so that they can be restored after evaluating more scripts
+	pub fn snapshot_variables(&self) -> VariableSnapshot {
+		VariableSnapshot {
+			variables: self.variables.clone(),
+			globals: self.globals.clone()
+		}
+	}
+	/// Replaces the variables and globals with the ones saved in the snapshot
+	pub fn restore_variables(&mut self, snapshot: &VariableSnapshot) {
+		self.variables.clone_from(&snapshot.variables);
+		self.globals.clone_from(&snapshot.globals);
+	}
+	/// Clears the cache, variables and globals. Options, the resolver and registered queries are kept
+	pub fn reset(&mut self) {
+		self.cache.clear();
+		self.nested_scripts.get_mut().clear();
+		self.variables.clear();
+		self.globals.clear();
+	}
+	/// Registers a `query.*` function. It can be used with or without parentheses, like `query.get_name()` or `query.get_name`,
+	/// and is called with however many arguments are passed
+	pub fn register_query(&mut self, name: &str, query: impl Fn(&[MolangValue]) -> MolangValue + 'static) {
+		self.register_query_with_context(name, move |_, args| query(args));
+	}
+	/// Registers a `query.*` function that can evaluate other scripts through the context, like a query built from Molang
+	pub fn register_query_with_context(&mut self, name: &str, query: impl Fn(&mut QueryContext, &[MolangValue]) -> MolangValue + 'static) {
+		self.queries.insert(normalize_name(name), Rc::new(query));
+	}
+	/// Registers a `query.*` function that takes and returns numbers
+	pub fn register_query_num(&mut self, name: &str, query: impl Fn(&[f32]) -> f32 + 'static) {
+		self.register_query(name, move |args| {
+			let numbers: Vec<f32> = args.iter().map(MolangValue::as_number).collect();
+			MolangValue::Number(query(&numbers))
+		});
+	}
+	/// Sets a resolver that is consulted for `variable.*` and `query.*` names that aren't set, before falling back to 0
+	pub fn set_variable_resolver(&mut self, resolver: Box<VariableResolver>) {
+		self.variable_resolver = Some(Rc::from(resolver));
+	}
+	/// Sets a hook that is called with the name and value of every variable read and query call, for debugging scripts
+	#[cfg(feature = "tracing")]
+	pub fn set_trace_hook(&mut self, hook: Box<TraceHook>) {
+		self.trace_hook = Some(Rc::from(hook));
+	}
+	/// Lists the variables in a script that are assigned and then assigned again before being read, which makes the first
+	/// assignment useless. Assignments that only happen in some branches or loop iterations aren't reported
+	pub fn dead_stores(&self, input: &str) -> Vec<String> {
+		let mut dead = Vec::new();
+		self.compile(input).expression.find_dead_stores(&mut Vec::new(), &mut dead);
+		dead
+	}
+	/// Adds a short prefix for a namespace, like `m` for `material` to read and assign `m.color` as `material.color`.
+	/// The built-in `q`, `v`, `t` and `c` prefixes can be overridden too. Clears the cache, since cached scripts may use the prefix
+	pub fn add_alias(&mut self, short: char, full: &str) {
+		self.aliases.insert(short.to_ascii_lowercase(), full.to_lowercase());
+		self.cache.clear();
+		self.nested_scripts.get_mut().clear();
+	}
+	/// Compiles a set of named scripts once, so they can be evaluated by name
+	pub fn compile_set(&self, scripts: &HashMap<String, &str>) -> MolangScriptSet {
+		MolangScriptSet {
+			scripts: scripts.iter().map(|(name, input)| (name.clone(), self.compile(input))).collect()
+		}
+	}
+	/// Parses the input into an expression tree without evaluating it
+	pub fn compile(&self, input: &str) -> CompiledMolang {
+		compile_script(input, &self.aliases, self.max_depth)
+	}
 }